@@ -0,0 +1,87 @@
+#include <stdint.h>
+#include <stdlib.h>
+#include <pthread.h>
+
+#include <lightning.h>
+#include "lightning-sys.h"
+
+/* The interface through which GDB finds code generated at run time, as
+ * described in "JIT Compilation Interface" in the GDB manual. The symbols are
+ * weak, so that another JIT compiler in the process may define them too. */
+
+typedef enum {
+    JIT_NOACTION = 0,
+    JIT_REGISTER_FN,
+    JIT_UNREGISTER_FN
+} jit_actions_t;
+
+struct jit_code_entry {
+    struct jit_code_entry *next_entry;
+    struct jit_code_entry *prev_entry;
+    const char *symfile_addr;
+    uint64_t symfile_size;
+};
+
+struct jit_descriptor {
+    uint32_t version;
+    uint32_t action_flag;
+    struct jit_code_entry *relevant_entry;
+    struct jit_code_entry *first_entry;
+};
+
+void __attribute__((weak, noinline)) __jit_debug_register_code(void)
+{
+    /* GDB sets a breakpoint here; keep the call from being optimized out. */
+    __asm__ __volatile__("");
+}
+
+struct jit_descriptor __attribute__((weak)) __jit_debug_descriptor = {
+    1, JIT_NOACTION, NULL, NULL
+};
+
+static pthread_mutex_t gdb_lock = PTHREAD_MUTEX_INITIALIZER;
+
+/* Tells GDB about the object file of `size` bytes at `image`, which must stay
+ * alive until it is passed to `lgsys_gdb_unregister`. Returns a handle for
+ * that, or NULL on failure. */
+jit_pointer_t lgsys_gdb_register(const void *image, jit_word_t size)
+{
+    struct jit_code_entry *entry = malloc(sizeof *entry);
+
+    if (!entry)
+        return NULL;
+    entry->symfile_addr = image;
+    entry->symfile_size = size;
+    entry->prev_entry = NULL;
+
+    pthread_mutex_lock(&gdb_lock);
+    entry->next_entry = __jit_debug_descriptor.first_entry;
+    if (entry->next_entry)
+        entry->next_entry->prev_entry = entry;
+    __jit_debug_descriptor.first_entry = entry;
+    __jit_debug_descriptor.relevant_entry = entry;
+    __jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
+    __jit_debug_register_code();
+    pthread_mutex_unlock(&gdb_lock);
+
+    return entry;
+}
+
+void lgsys_gdb_unregister(jit_pointer_t handle)
+{
+    struct jit_code_entry *entry = handle;
+
+    pthread_mutex_lock(&gdb_lock);
+    if (entry->prev_entry)
+        entry->prev_entry->next_entry = entry->next_entry;
+    else
+        __jit_debug_descriptor.first_entry = entry->next_entry;
+    if (entry->next_entry)
+        entry->next_entry->prev_entry = entry->prev_entry;
+    __jit_debug_descriptor.relevant_entry = entry;
+    __jit_debug_descriptor.action_flag = JIT_UNREGISTER_FN;
+    __jit_debug_register_code();
+    pthread_mutex_unlock(&gdb_lock);
+
+    free(entry);
+}
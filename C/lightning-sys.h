@@ -10,4 +10,91 @@ int lgsys_JIT_V_NUM(void);
 int lgsys_JIT_F_NUM(void);
 
 extern const int lgsys_JIT_FP;
+
+/* A mapping produced by `jit_emit`. `owned` is zero when the memory was
+ * supplied by the user (through `jit_set_code` or `jit_set_data`) and must
+ * not be released by us. */
+struct lgsys_buffer {
+    jit_pointer_t ptr;
+    jit_word_t length;
+    jit_bool_t owned;
+};
+
+void lgsys_take_buffers(jit_state_t *_jit,
+                        struct lgsys_buffer *code,
+                        struct lgsys_buffer *data);
+void lgsys_release_buffer(struct lgsys_buffer *buffer);
+jit_pointer_t lgsys_map_code(jit_word_t length);
+jit_pointer_t lgsys_map_writable(jit_word_t length);
+jit_pointer_t lgsys_map_dual(jit_word_t length, jit_pointer_t *alias);
+int lgsys_protect(jit_pointer_t ptr, jit_word_t length, int executable);
+void lgsys_flush(jit_pointer_t ptr, jit_word_t length);
+void lgsys_unmap(jit_pointer_t ptr, jit_word_t length);
+jit_word_t lgsys_page_size(void);
+void lgsys_realize(jit_state_t *_jit);
+void lgsys_data_sizes(jit_state_t *_jit, jit_word_t *data, jit_word_t *note);
+jit_word_t lgsys_data_flags(jit_state_t *_jit);
+void lgsys_forget_code(jit_state_t *_jit);
+void lgsys_forget_data(jit_state_t *_jit, jit_word_t flags);
+
+/* A position in the notes of a state, as walked by `lgsys_next_note`. */
+struct lgsys_note_cursor {
+    jit_word_t note;
+    jit_word_t line;
+    jit_word_t offset;
+};
+
+/* A line of a file within the code of a note. `code` and `size` describe the
+ * whole code that the note covers, `address` where the line starts. */
+struct lgsys_note {
+    jit_uint8_t *code;
+    jit_word_t size;
+    const char *name;
+    const char *file;
+    jit_int32_t line;
+    jit_uint8_t *address;
+};
+
+jit_bool_t lgsys_next_note(jit_state_t *_jit,
+                           struct lgsys_note_cursor *cursor,
+                           struct lgsys_note *entry);
+
+jit_bool_t lgsys_note_function(jit_state_t *_jit,
+                               jit_word_t index,
+                               struct lgsys_note *entry);
+
+/* A function of an emitted state, as described by `lgsys_frames`. */
+struct lgsys_frame {
+    jit_pointer_t epilog;
+    jit_word_t saved;
+};
+
+jit_word_t lgsys_frames(jit_state_t *_jit,
+                        struct lgsys_frame *frames,
+                        jit_word_t capacity);
+
+/* Statistics about the nodes of a state, as gathered by `lgsys_stats`. */
+struct lgsys_stats {
+    jit_word_t nodes[7];
+    jit_word_t labels;
+    jit_word_t forwards;
+    jit_word_t patches;
+    jit_word_t estimated;
+};
+
+jit_bool_t lgsys_stats(jit_state_t *_jit, struct lgsys_stats *stats);
+
+char *lgsys_print(jit_state_t *_jit, jit_word_t *length);
+char *lgsys_disassemble(jit_state_t *_jit, jit_word_t *length);
+int lgsys_has_disassembler(void);
+void lgsys_free(void *ptr);
+
+jit_pointer_t lgsys_gdb_register(const void *image, jit_word_t size);
+void lgsys_gdb_unregister(jit_pointer_t handle);
+
+/* Only available on Linux. */
+jit_word_t lgsys_perf_timestamp(void);
+jit_word_t lgsys_perf_tid(void);
+jit_pointer_t lgsys_perf_mark(int fd);
+void lgsys_perf_unmark(jit_pointer_t mark);
 #endif
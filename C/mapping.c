@@ -0,0 +1,93 @@
+#define _GNU_SOURCE
+#include <errno.h>
+#include <sys/mman.h>
+#include <unistd.h>
+#ifdef __linux__
+#include <sys/syscall.h>
+#endif
+
+#include <lightning.h>
+#include "lightning-sys.h"
+
+/* Maps `length` bytes that can be written and executed, to hold code placed
+ * with `jit_set_code`. Returns NULL on failure. */
+jit_pointer_t lgsys_map_code(jit_word_t length)
+{
+    void *ptr = mmap(NULL, length, PROT_READ | PROT_WRITE | PROT_EXEC,
+                     MAP_PRIVATE | MAP_ANON, -1, 0);
+    return ptr == MAP_FAILED ? NULL : ptr;
+}
+
+/* Maps `length` bytes that can be written but not executed, until
+ * `lgsys_protect` says otherwise. Returns NULL on failure. */
+jit_pointer_t lgsys_map_writable(jit_word_t length)
+{
+    void *ptr = mmap(NULL, length, PROT_READ | PROT_WRITE,
+                     MAP_PRIVATE | MAP_ANON, -1, 0);
+    return ptr == MAP_FAILED ? NULL : ptr;
+}
+
+/* Maps `length` bytes of a memory file twice, both times writable: the
+ * returned mapping is meant to hold code and be made executable with
+ * `lgsys_protect`, while `*alias` stays writable for patching it. Returns
+ * NULL on failure, or where memory files are not available. */
+jit_pointer_t lgsys_map_dual(jit_word_t length, jit_pointer_t *alias)
+{
+#if defined(__linux__) && defined(SYS_memfd_create)
+    void *code, *data;
+    int fd = syscall(SYS_memfd_create, "lightning", 1 /* MFD_CLOEXEC */);
+    if (fd < 0)
+        return NULL;
+    if (ftruncate(fd, length) != 0) {
+        close(fd);
+        return NULL;
+    }
+
+    code = mmap(NULL, length, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    data = mmap(NULL, length, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    close(fd);
+    if (code == MAP_FAILED || data == MAP_FAILED) {
+        if (code != MAP_FAILED)
+            munmap(code, length);
+        if (data != MAP_FAILED)
+            munmap(data, length);
+        return NULL;
+    }
+
+    *alias = data;
+    return code;
+#else
+    (void)length;
+    (void)alias;
+    return NULL;
+#endif
+}
+
+/* Makes the mapping at `ptr` readable and executable, or readable and
+ * writable, and flushes the instruction cache after writes. Returns zero on
+ * success, and an `errno` value otherwise. */
+int lgsys_protect(jit_pointer_t ptr, jit_word_t length, int executable)
+{
+    int prot = PROT_READ | (executable ? PROT_EXEC : PROT_WRITE);
+    if (mprotect(ptr, length, prot) != 0)
+        return errno;
+    if (executable)
+        lgsys_flush(ptr, length);
+    return 0;
+}
+
+/* Makes writes to the code at `ptr` visible to instruction fetches. */
+void lgsys_flush(jit_pointer_t ptr, jit_word_t length)
+{
+    __builtin___clear_cache((char *)ptr, (char *)ptr + length);
+}
+
+void lgsys_unmap(jit_pointer_t ptr, jit_word_t length)
+{
+    munmap(ptr, length);
+}
+
+jit_word_t lgsys_page_size(void)
+{
+    return sysconf(_SC_PAGESIZE);
+}
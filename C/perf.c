@@ -0,0 +1,38 @@
+#ifdef __linux__
+#define _GNU_SOURCE
+#include <time.h>
+#include <unistd.h>
+#include <sys/mman.h>
+#include <sys/syscall.h>
+
+#include <lightning.h>
+#include "lightning-sys.h"
+
+/* perf orders jitdump records by CLOCK_MONOTONIC, whose raw value
+ * `std::time::Instant` does not expose. */
+jit_word_t lgsys_perf_timestamp(void)
+{
+    struct timespec ts;
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return (jit_word_t)ts.tv_sec * 1000000000 + ts.tv_nsec;
+}
+
+jit_word_t lgsys_perf_tid(void)
+{
+    return syscall(SYS_gettid);
+}
+
+/* `perf record` finds a jitdump file through an executable mapping of it,
+ * which this creates. Returns NULL on failure. */
+jit_pointer_t lgsys_perf_mark(int fd)
+{
+    void *mark = mmap(NULL, sysconf(_SC_PAGESIZE), PROT_READ | PROT_EXEC,
+                      MAP_PRIVATE, fd, 0);
+    return mark == MAP_FAILED ? NULL : mark;
+}
+
+void lgsys_perf_unmark(jit_pointer_t mark)
+{
+    munmap(mark, sysconf(_SC_PAGESIZE));
+}
+#endif
@@ -0,0 +1,89 @@
+#define _GNU_SOURCE
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#include <lightning.h>
+#include "lightning-sys.h"
+
+/* Runs `jit_print` with the C `stdout` pointed at a memory stream, and
+ * returns the listing it wrote, or NULL if the stream could not be opened.
+ * The caller must release the returned buffer with `lgsys_free`, and must
+ * make sure that nothing else writes to `stdout` in the meantime. */
+char *lgsys_print(jit_state_t *_jit, jit_word_t *length)
+{
+    char *buffer = NULL;
+    size_t size = 0;
+    FILE *stream = open_memstream(&buffer, &size);
+    FILE *saved;
+
+    if (!stream)
+        return NULL;
+
+    fflush(stdout);
+    saved = stdout;
+    stdout = stream;
+    _jit_print(_jit);
+    stdout = saved;
+
+    if (fclose(stream)) {
+        free(buffer);
+        return NULL;
+    }
+
+    *length = size;
+    return buffer;
+}
+
+/* Runs `jit_disassemble` with file descriptor 1 pointed at a temporary file,
+ * since the disassembler keeps its own copy of the `stdout` stream, and
+ * returns what it wrote like `lgsys_print` does. */
+char *lgsys_disassemble(jit_state_t *_jit, jit_word_t *length)
+{
+    FILE *capture = tmpfile();
+    char *buffer = NULL;
+    off_t size;
+    int fd, saved;
+
+    if (!capture)
+        return NULL;
+    fd = fileno(capture);
+
+    fflush(stdout);
+    saved = dup(STDOUT_FILENO);
+    if (saved < 0 || dup2(fd, STDOUT_FILENO) < 0)
+        goto done;
+    _jit_disassemble(_jit);
+    fflush(stdout);
+    dup2(saved, STDOUT_FILENO);
+
+    size = lseek(fd, 0, SEEK_END);
+    if (size < 0 || lseek(fd, 0, SEEK_SET) < 0)
+        goto done;
+    buffer = malloc(size + 1);
+    if (buffer && read(fd, buffer, size) != size) {
+        free(buffer);
+        buffer = NULL;
+    }
+    *length = size;
+
+done:
+    if (saved >= 0)
+        close(saved);
+    fclose(capture);
+    return buffer;
+}
+
+int lgsys_has_disassembler(void)
+{
+#if DISASSEMBLER
+    return 1;
+#else
+    return 0;
+#endif
+}
+
+void lgsys_free(void *ptr)
+{
+    free(ptr);
+}
@@ -0,0 +1,180 @@
+#include <sys/mman.h>
+
+#include <lightning.h>
+#include <lightning/jit_private.h>
+#include "lightning-sys.h"
+
+/* Detaches the code and data mappings created by `jit_emit` from `_jit`, so
+ * that neither `jit_clear_state` nor `jit_destroy_state` releases them. The
+ * emitted code refers to constants in the data mapping, so both must live
+ * exactly as long as the code does. */
+void lgsys_take_buffers(jit_state_t *_jit,
+                        struct lgsys_buffer *code,
+                        struct lgsys_buffer *data)
+{
+    code->ptr = _jit->code.ptr;
+    code->length = _jit->code.length;
+    code->owned = !_jit->user_code;
+    _jit->user_code = 1;
+
+    data->ptr = _jit->data.ptr;
+    data->length = _jit->data.length;
+    data->owned = !_jit->user_data;
+    _jit->user_data = 1;
+}
+
+/* Realizes `_jit` unless that has already been done; lightning asserts that it
+ * only happens once. */
+void lgsys_realize(jit_state_t *_jit)
+{
+    if (!_jitc->realize)
+        _jit_realize(_jit);
+}
+
+/* Reports how many bytes at the start of the data buffer of the emitted `_jit`
+ * hold constants, and how many after them hold notes. */
+void lgsys_data_sizes(jit_state_t *_jit, jit_word_t *data, jit_word_t *note)
+{
+    *data = _jitc->no_data ? 0 : _jitc->data.offset;
+    *note = _jitc->no_note ? 0 : _jitc->note.size;
+}
+
+/* Returns the flags given to `jit_set_data` for `_jit`. */
+jit_word_t lgsys_data_flags(jit_state_t *_jit)
+{
+    return (_jitc->no_data ? JIT_DISABLE_DATA : 0)
+        | (_jitc->no_note ? JIT_DISABLE_NOTE : 0);
+}
+
+/* Makes `jit_emit` map memory for the code of `_jit` itself again, forgetting
+ * the buffer given to `jit_set_code`, which may be gone by the time `_jit` is
+ * emitted. */
+void lgsys_forget_code(jit_state_t *_jit)
+{
+    _jit->user_code = 0;
+    _jit->code.ptr = NULL;
+    _jit->code.length = 0;
+}
+
+/* Likewise for the buffer given to `jit_set_data`, and sets the flags of
+ * `_jit` back to `flags`. */
+void lgsys_forget_data(jit_state_t *_jit, jit_word_t flags)
+{
+    _jit->user_data = 0;
+    _jit->data.ptr = NULL;
+    _jit->data.length = 0;
+    _jitc->no_data = (flags & JIT_DISABLE_DATA) != 0;
+    _jitc->no_note = (flags & JIT_DISABLE_NOTE) != 0;
+}
+
+void lgsys_release_buffer(struct lgsys_buffer *buffer)
+{
+    if (buffer->owned && buffer->ptr)
+        munmap(buffer->ptr, buffer->length);
+    buffer->ptr = NULL;
+    buffer->length = 0;
+    buffer->owned = 0;
+}
+
+/* Reads the entry of the notes of `_jit` at `cursor`, and advances `cursor`
+ * past it. Each entry is a line of a file within the code covered by a note;
+ * notes without any lines have no entries. Returns zero after the last one. */
+jit_bool_t lgsys_next_note(jit_state_t *_jit,
+                           struct lgsys_note_cursor *cursor,
+                           struct lgsys_note *entry)
+{
+    while (cursor->note < _jit->note.length) {
+        jit_note_t *note = _jit->note.ptr + cursor->note;
+        jit_line_t *line;
+
+        if (cursor->line >= note->length) {
+            ++cursor->note;
+            cursor->line = 0;
+            continue;
+        }
+
+        line = note->lines + cursor->line;
+        if (cursor->offset >= line->length) {
+            ++cursor->line;
+            cursor->offset = 0;
+            continue;
+        }
+
+        entry->code = note->code;
+        entry->size = note->size;
+        entry->name = note->name;
+        entry->file = line->file;
+        entry->line = line->linenos[cursor->offset];
+        entry->address = note->code + line->offsets[cursor->offset];
+        ++cursor->offset;
+        return 1;
+    }
+
+    return 0;
+}
+
+/* Describes the code covered by the `index`th note of `_jit`, which starts
+ * at a `jit_name`, leaving the file and line of `entry` empty. Returns zero
+ * if there is no such note. */
+jit_bool_t lgsys_note_function(jit_state_t *_jit,
+                               jit_word_t index,
+                               struct lgsys_note *entry)
+{
+    jit_note_t *note;
+
+    if (index < 0 || index >= _jit->note.length)
+        return 0;
+
+    note = _jit->note.ptr + index;
+    entry->code = note->code;
+    entry->size = note->size;
+    entry->name = note->name;
+    entry->file = NULL;
+    entry->line = 0;
+    entry->address = note->code;
+    return 1;
+}
+
+/* The callee-saved registers that lightning's prolog stores in the frame, in
+ * the order of their slots; see `_prolog` in jit_x86-cpu.c and
+ * jit_aarch64-cpu.c. */
+#if defined(__x86_64__) && !defined(_WIN32)
+static const jit_int32_t frame_saves[] = { _RBX, _R12, _R13, _R14, _R15 };
+#elif defined(__aarch64__)
+static const jit_int32_t frame_saves[] = {
+    _R19, _R20, _R21, _R22, _R23, _R24, _R25, _R26, _R27, _R28,
+    _V8, _V9, _V10, _V11, _V12, _V13, _V14, _V15
+};
+#endif
+
+/* Describes each function of the emitted `_jit`, in order: the address of its
+ * epilog, and which callee-saved registers its prolog stored, bit `i` of
+ * `saved` standing for the `i`th slot of the frame. Writes at most `capacity`
+ * entries, and returns the number of functions, or zero if the frame layout
+ * of the target is unknown. */
+jit_word_t lgsys_frames(jit_state_t *_jit,
+                        struct lgsys_frame *frames,
+                        jit_word_t capacity)
+{
+    jit_word_t count = 0;
+#if (defined(__x86_64__) && !defined(_WIN32)) || defined(__aarch64__)
+    jit_word_t length = sizeof frame_saves / sizeof frame_saves[0];
+    jit_node_t *node;
+    jit_word_t i;
+
+    for (node = _jitc->head; node; node = node->next) {
+        if (node->code != jit_code_epilog)
+            continue;
+        if (count < capacity) {
+            jit_function_t *function = _jitc->functions.ptr + node->w.w;
+            frames[count].epilog = node->u.p;
+            frames[count].saved = 0;
+            for (i = 0; i < length; i++)
+                if (jit_regset_tstbit(&function->regset, frame_saves[i]))
+                    frames[count].saved |= (jit_word_t)1 << i;
+        }
+        count++;
+    }
+#endif
+    return count;
+}
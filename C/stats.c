@@ -0,0 +1,112 @@
+#include <stdlib.h>
+
+#include <lightning.h>
+#include <lightning/jit_private.h>
+#include "lightning-sys.h"
+
+/* The categories of nodes, in the order of `NodeCategory` in src/stats.rs. */
+enum {
+    CATEGORY_PSEUDO,
+    CATEGORY_ARGUMENT,
+    CATEGORY_INTEGER,
+    CATEGORY_FLOAT,
+    CATEGORY_MEMORY,
+    CATEGORY_BRANCH,
+    CATEGORY_CALL,
+};
+
+/* Returns the category of `code`, from the ranges that each kind of
+ * instruction occupies in `jit_code_t`; see lightning.h. The single and
+ * double precision instructions repeat the layout of `arg_f` to `retval_f`. */
+static int category(jit_code_t code)
+{
+    if (code <= jit_code_label || code == jit_code_prolog || code == jit_code_epilog)
+        return CATEGORY_PSEUDO;
+    if (code < jit_code_addr)
+        return CATEGORY_ARGUMENT;
+    if (code < jit_code_ldr_c)
+        return CATEGORY_INTEGER;
+    if (code < jit_code_bltr)
+        return CATEGORY_MEMORY;
+    if (code < jit_code_callr)
+        return CATEGORY_BRANCH;
+    if (code < jit_code_epilog)
+        return CATEGORY_CALL;
+    if (code >= jit_code_movr_w_f)
+        return CATEGORY_FLOAT;
+    if (code >= jit_code_arg_d)
+        code = code - jit_code_arg_d + jit_code_arg_f;
+    if (code < jit_code_addr_f)
+        return CATEGORY_ARGUMENT;
+    if (code < jit_code_ldr_f)
+        return CATEGORY_FLOAT;
+    if (code < jit_code_bltr_f)
+        return CATEGORY_MEMORY;
+    if (code < jit_code_pushargr_f)
+        return CATEGORY_BRANCH;
+    return CATEGORY_CALL;
+}
+
+/* A node, and where it comes in its state. */
+struct position {
+    jit_node_t *node;
+    jit_word_t index;
+};
+
+static int compare_positions(const void *a, const void *b)
+{
+    const struct position *x = a, *y = b;
+    return (x->node > y->node) - (x->node < y->node);
+}
+
+static jit_word_t index_of(struct position *positions, jit_word_t count, jit_node_t *node)
+{
+    struct position key = { node, 0 };
+    struct position *found = bsearch(&key, positions, count, sizeof key, compare_positions);
+    return found ? found->index : -1;
+}
+
+/* Gathers statistics about the nodes of `_jit`: how many there are of each
+ * category, how many labels, how many of those are referred to before they
+ * appear, how many instructions refer to a label, and lightning's estimate of
+ * the size of the code. Returns zero if it runs out of memory. */
+jit_bool_t lgsys_stats(jit_state_t *_jit, struct lgsys_stats *stats)
+{
+    struct position *positions;
+    jit_node_t *node, *patch;
+    jit_word_t count = 0, i;
+
+    for (i = 0; i < (jit_word_t)(sizeof stats->nodes / sizeof stats->nodes[0]); i++)
+        stats->nodes[i] = 0;
+    stats->labels = stats->forwards = stats->patches = 0;
+    stats->estimated = _jit_get_size(_jit);
+
+    for (node = _jitc->head; node; node = node->next)
+        count++;
+    positions = malloc((count ? count : 1) * sizeof *positions);
+    if (!positions)
+        return 0;
+    for (i = 0, node = _jitc->head; node; node = node->next, i++) {
+        positions[i].node = node;
+        positions[i].index = i;
+        stats->nodes[category(node->code)]++;
+    }
+    qsort(positions, count, sizeof *positions, compare_positions);
+
+    for (i = 0, node = _jitc->head; node; node = node->next, i++) {
+        jit_bool_t forward = 0;
+        if (node->code != jit_code_label)
+            continue;
+        stats->labels++;
+        /* Instructions patched to refer to a label are chained from it. */
+        for (patch = node->link; patch; patch = patch->link) {
+            stats->patches++;
+            if (index_of(positions, count, patch) < i)
+                forward = 1;
+        }
+        stats->forwards += forward;
+    }
+
+    free(positions);
+    return 1;
+}
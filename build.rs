@@ -6,7 +6,7 @@ use std::env;
 use std::io::Write;
 use std::ops::Index;
 use std::panic::AssertUnwindSafe;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 // We need the interior mutability of `RefCell` to work around the fact that the
@@ -244,6 +244,46 @@ fn make_printable(collected: Vec<Record>) -> Vec<String> {
         .collect()
 }
 
+/// Checks whether the binutils headers that lightning's disassembler needs are
+/// installed. Returns the definitions to compile lightning with if they are, or
+/// `None` if they are missing or too new: lightning 2.1.3 predates the extra
+/// parameter that binutils 2.39 added to `init_disassemble_info`.
+fn probe_disassembler(out_path: &Path) -> Option<Vec<(&'static str, Option<&'static str>)>> {
+    // `bfd.h` refuses to be included unless it looks like `config.h` was.
+    const PACKAGE: (&str, Option<&str>) = ("PACKAGE", Some("\"lightning\""));
+
+    let compiles = |name: &str, body: &str| {
+        let file = out_path.join(format!("{}.c", name));
+        std::fs::write(&file, format!("#include <dis-asm.h>\n{}\n", body)).unwrap();
+        cc::Build::new()
+            .define(PACKAGE.0, PACKAGE.1)
+            .file(&file)
+            .cargo_metadata(false)
+            .warnings(false)
+            .try_compile(name)
+            .is_ok()
+    };
+
+    let init_info = "void f(struct disassemble_info *i) { init_disassemble_info(i, 0, 0); }";
+    if !compiles("probe_init_info", init_info) {
+        println!("cargo:warning=the `disassembler` feature needs the headers of \
+                  libopcodes and libbfd from binutils 2.38 or older; \
+                  disassembly is disabled");
+        return None;
+    }
+
+    let mut defines = vec![PACKAGE, ("DISASSEMBLER", Some("1"))];
+
+    // `disassembler()` takes the architecture, endianness and machine in
+    // addition to the `bfd` since binutils 2.29.
+    let four_args = "disassembler_ftype f(bfd *b) { return disassembler(bfd_arch_unknown, 0, 0, b); }";
+    if compiles("probe_binutils_2_29", four_args) {
+        defines.push(("BINUTILS_2_29", Some("1")));
+    }
+
+    Some(defines)
+}
+
 fn main() -> std::io::Result<()> {
     use std::io::{BufRead, BufReader};
     use std::fs::File;
@@ -275,6 +315,12 @@ fn main() -> std::io::Result<()> {
         ("NDEBUG", None),
     ];
 
+    let disassembler = if env::var_os("CARGO_FEATURE_DISASSEMBLER").is_some() {
+        probe_disassembler(&out_path)
+    } else {
+        None
+    };
+
     let files = &[
         libdir.join("jit_disasm.c"),
         libdir.join("jit_memory.c"),
@@ -291,6 +337,14 @@ fn main() -> std::io::Result<()> {
         builder.define(name, val);
     }
 
+    if let Some(defines) = disassembler {
+        for (name, val) in defines {
+            builder.define(name, val);
+        }
+        println!("cargo:rustc-link-lib=opcodes");
+        println!("cargo:rustc-link-lib=bfd");
+    }
+
     for file in files {
         builder.file(file);
         // N.B.: This does not catch .c and .h files that are #include'd by the
@@ -299,12 +353,24 @@ fn main() -> std::io::Result<()> {
     }
 
     println!("cargo:rerun-if-changed={}", "C/register.c");
+    println!("cargo:rerun-if-changed={}", "C/state.c");
+    println!("cargo:rerun-if-changed={}", "C/stats.c");
+    println!("cargo:rerun-if-changed={}", "C/print.c");
+    println!("cargo:rerun-if-changed={}", "C/perf.c");
+    println!("cargo:rerun-if-changed={}", "C/gdb.c");
+    println!("cargo:rerun-if-changed={}", "C/mapping.c");
     println!("cargo:rerun-if-changed={}", "C/lightning-sys.h");
 
     builder
         .include(incdir.clone())
         .include(out_path.clone())
         .file("C/register.c")
+        .file("C/state.c")
+        .file("C/stats.c")
+        .file("C/print.c")
+        .file("C/perf.c")
+        .file("C/gdb.c")
+        .file("C/mapping.c")
         .flag_if_supported("-Wno-unused")
         .flag_if_supported("-Wno-unused-parameter")
         .compile("lightningsys");
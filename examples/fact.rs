@@ -1,12 +1,13 @@
 use lightning_sys::{Jit, JitWord, Reg, NULL};
 
-// Create constants with the same names as in the original example, to ease
-// comparison between the Rust and C versions.
-const JIT_R0: Reg = Reg::R(0);
-const JIT_R1: Reg = Reg::R(1);
-
 #[allow(clippy::print_literal)]
+#[allow(non_snake_case)]
 fn main() {
+    // Create bindings with the same names as in the original example, to
+    // ease comparison between the Rust and C versions.
+    let JIT_R0 = Reg::R(0);
+    let JIT_R1 = Reg::R(1);
+
     let mut j = Jit::new();
     let mut js = j.new_state();
 
@@ -14,7 +15,7 @@ fn main() {
     let fact = js.forward();
 
     js.prolog();                        /* Entry point of the factorial function */
-    let inp = js.arg();                 /* Receive an integer argument */
+    let inp = js.arg::<JitWord>();      /* Receive an integer argument */
     js.getarg(JIT_R0, &inp);            /* Move argument to RO */
     js.prepare();
     js.pushargi(1);                     /* This is the accumulator */
@@ -33,8 +34,8 @@ fn main() {
     js.prolog();
     js.frame(16);                       /* Reserve 16 bytes in the stack */
     let fact_entry = js.label();        /* This is the tail call entry point */
-    let ac = js.arg();                  /* The accumulator is the first argument */
-    let inp = js.arg();                 /* The factorial argument */
+    let ac = js.arg::<JitWord>();       /* The accumulator is the first argument */
+    let inp = js.arg::<JitWord>();      /* The factorial argument */
     js.getarg(JIT_R0, &ac);             /* Move the accumulator to R0 */
     js.getarg(JIT_R1, &inp);            /* Move the argument to R1 */
     let fact_out = js.blei(JIT_R1, 1);  /* Done if argument is one or less */
@@ -47,14 +48,14 @@ fn main() {
     js.patch(&fact_out);
     js.retr(JIT_R0);                    /* Return the accumulator */
 
-    let factorial = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
+    let js = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
     /* no need to query information about resolved addresses */
-    js.clear_state();
+    let factorial = js.clear_state();
 
     let arg = std::env::args().nth(1).map(|x| x.parse().unwrap_or(0)).unwrap_or(5);
 
     /* call the generated code */
-    println!("factorial({}) = {}", arg, factorial(arg));
+    println!("factorial({}) = {}", arg, factorial.call(arg));
     /* release all memory associated with the _jit identifier */
     /* (this happens automatically with Drop in the Rust version) */
 }
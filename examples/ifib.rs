@@ -1,19 +1,20 @@
 use lightning_sys::{Jit, JitWord, Reg};
 
-// Create constants with the same names as in the original example, to ease
-// comparison between the Rust and C versions.
-const JIT_R0: Reg = Reg::R(0);
-const JIT_R1: Reg = Reg::R(1);
-const JIT_R2: Reg = Reg::R(2);
-const JIT_V0: Reg = Reg::V(0);
-
 #[allow(clippy::print_literal)]
+#[allow(non_snake_case)]
 fn main() {
+    // Create bindings with the same names as in the original example, to
+    // ease comparison between the Rust and C versions.
+    let JIT_R0 = Reg::R(0);
+    let JIT_R1 = Reg::R(1);
+    let JIT_R2 = Reg::R(2);
+    let JIT_V0 = Reg::V(0);
+
     let mut j = Jit::new();
     let mut js = j.new_state();
 
                 js.prolog   ();
-    let inp =   js.arg      ();
+    let inp =   js.arg::<JitWord>();
                 js.getarg   (JIT_R0, &inp);             /* R0 = n */
     let zero =  js.beqi     (JIT_R0, 0);
                 js.movr     (JIT_R1, JIT_R0);
@@ -34,9 +35,10 @@ fn main() {
     js.patch(&zero);                                    /* patch forward jump */
                 js.retr     (JIT_R0);
 
-    let fib = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-    js.clear_state();
+    let js = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
+
+    let fib = js.clear_state();
 
-    println!("fib({}) = {}", 36, fib(36));
+    println!("fib({}) = {}", 36, fib.call(36));
 }
 
@@ -1,24 +1,19 @@
 use lightning_sys::{Jit, JitWord, Reg};
 
-// Create constants with the same names as in the original example, to ease
-// comparison between the Rust and C versions.
-const JIT_R0: Reg = Reg::R(0);
-
 #[allow(clippy::print_literal)]
 fn main() {
     let mut j = Jit::new();
     let mut js = j.new_state();
 
-    js.prolog();
-    let inp = js.arg();
-    js.getarg(JIT_R0, &inp);
-    js.addi(JIT_R0, JIT_R0, 1);
-    js.retr(JIT_R0);
+    /* the argument arrives in a register, and the return matches the signature */
+    let incr = js.function::<extern "C" fn(JitWord) -> JitWord, _>(|mut f, (inp,)| {
+        f.addi(Reg::R(0), inp, 1);
+        f.retr(Reg::R(0))
+    }).unwrap();
 
-    let incr = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-    js.clear_state();
+    let js = js.emit().unwrap();
+    let incr = incr.resolve(&js);
 
     /* call the generated code, passing 5 as an argument */
-    println!("{} + 1 = {}", 5, incr(5));
+    println!("{} + 1 = {}", 5, incr.call(5));
 }
-
@@ -2,22 +2,22 @@ use lightning_sys::{Jit, JitWord, JitPointer, Reg};
 use std::ffi::CString;
 use std::convert::TryInto;
 
-// Create constants with the same names as in the original example, to ease
-// comparison between the Rust and C versions.
-const JIT_R1: Reg = Reg::R(1);
-
 #[allow(clippy::print_literal)]
 #[allow(non_snake_case)]
 fn main() {
+    // Create bindings with the same names as in the original example, to
+    // ease comparison between the Rust and C versions.
+    let JIT_R1 = Reg::R(1);
+
     let mut j = Jit::new();
     let mut js = j.new_state();
 
     // make sure this outlives any calls
     let cs = CString::new("generated %d bytes\n").unwrap();
 
-    let start = js.note(Some(file!()), line!());
+    let start = js.note(Some(file!()), line!()).unwrap();
     js.prolog();
-    let inp = js.arg();
+    let inp = js.arg::<JitWord>();
     js.getarg(JIT_R1, &inp);
     js.prepare();
     js.pushargi(cs.as_ptr() as JitWord);
@@ -26,14 +26,19 @@ fn main() {
     js.finishi(libc::printf as JitPointer);
     js.ret();
     js.epilog();
-    let end = js.note(Some(file!()), line!());
 
-    let myFunction = unsafe{ js.cast_emit::<extern fn(JitWord)>() };
+    let js = unsafe{ js.cast_emit::<extern fn(JitWord)>() }.unwrap();
+    let size = js.function_range(&start).len();
 
-    /* call the generated code, passing its size as argument */
-    myFunction((js.address(&end) as usize - js.address(&start) as usize).try_into().unwrap());
-    js.clear_state();
+    #[cfg(feature = "disassembler")]
+    match js.disassembly() {
+        Ok(text) => print!("{}", text),
+        Err(e) => eprintln!("{}", e),
+    }
 
-    // js.disassemble(); // TODO support disassembly
+    let myFunction = js.clear_state();
+
+    /* call the generated code, passing its size as argument */
+    myFunction.call(size.try_into().unwrap());
 }
 
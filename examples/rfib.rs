@@ -1,20 +1,21 @@
 use lightning_sys::{Jit, JitWord, Reg, NULL};
 
-// Create constants with the same names as in the original example, to ease
-// comparison between the Rust and C versions.
-const JIT_R0: Reg = Reg::R(0);
-const JIT_V0: Reg = Reg::V(0);
-const JIT_V1: Reg = Reg::V(1);
-const JIT_V2: Reg = Reg::V(2);
-
 #[allow(clippy::print_literal)]
+#[allow(non_snake_case)]
 fn main() {
+    // Create bindings with the same names as in the original example, to
+    // ease comparison between the Rust and C versions.
+    let JIT_R0 = Reg::R(0);
+    let JIT_V0 = Reg::V(0);
+    let JIT_V1 = Reg::V(1);
+    let JIT_V2 = Reg::V(2);
+
     let mut j = Jit::new();
     let mut js = j.new_state();
 
     let label = js.label    ();
                 js.prolog   ();
-    let inp =   js.arg      ();
+    let inp =   js.arg::<JitWord>();
                 js.getarg   (JIT_R0, &inp);             /* R0 = n */
     let zero =  js.beqi     (JIT_R0, 0);
                 js.movr     (JIT_V0, JIT_R0);           /* V0 = R0 */
@@ -38,9 +39,10 @@ fn main() {
     js.patch(&zero);                                    /* patch jump */
                 js.retr(JIT_R0);
 
-    let fib = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-    js.clear_state();
+    let js = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
+
+    let fib = js.clear_state();
 
-    println!("fib({}) = {}", 32, fib(32));
+    println!("fib({}) = {}", 32, fib.call(32));
 }
 
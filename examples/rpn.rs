@@ -5,19 +5,19 @@ use lightning_sys::*;
 use core::mem::size_of;
 
 fn stack_push(js: &mut JitState, reg: Reg, sp: &mut c_int) {
-    js.stxi_i((*sp).into(), Reg::FP, reg);
+    js.stxi_i((*sp).into(), Base::FP, reg);
     *sp += size_of::<c_int>() as c_int;
 }
 
 fn stack_pop(js: &mut JitState, reg: Reg, sp: &mut c_int) {
     *sp -= size_of::<c_int>() as c_int;
-    js.ldxi_i(reg, Reg::FP, (*sp).into());
+    js.ldxi_i(reg, Base::FP, (*sp).into());
 }
 
-fn compile_rpn<'a>(js: &mut JitState<'a>, mut expr: &str) -> JitNode<'a> {
-    let func = js.note(None, 0);
+fn compile_rpn<'a>(js: &mut JitState<'a>, mut expr: &str) -> Note<'a> {
+    let func = js.note(None, 0).unwrap();
     js.prolog();
-    let inp = js.arg();
+    let inp = js.arg::<c_int>();
     let stack_base = js.allocai(32 * size_of::<c_int>() as c_int);
     let mut stack_ptr = stack_base;
 
@@ -70,27 +70,23 @@ fn main() {
     let nc = compile_rpn(&mut js, "32x9*5/+");
     let nf = compile_rpn(&mut js, "x32-5*9/");
 
-    let _ = js.emit();
-
-    unsafe fn to_func<T,R>(ptr: JitPointer) -> extern "C" fn(T) -> R {
-        *(&ptr as *const *mut core::ffi::c_void as *const extern "C" fn(T) -> R)
-    }
-
+    let js = js.emit().unwrap();
     let c2f = js.address(&nc);
-    let c2f = unsafe { to_func::<_, c_int>(c2f) };
     let f2c = js.address(&nf);
-    let f2c = unsafe { to_func::<_, c_int>(f2c) };
-    js.clear_state();
+    let code = js.into_code();
+
+    let c2f = unsafe { code.at::<extern "C" fn(c_int) -> c_int>(c2f) };
+    let f2c = unsafe { code.at::<extern "C" fn(c_int) -> c_int>(f2c) };
 
     print!("\nC:");
     for i in 0..=10 { print!("{:3} ", i * 10); }
     print!("\nF:");
-    for i in 0..=10 { print!("{:3} ", c2f(i * 10)); }
+    for i in 0..=10 { print!("{:3} ", c2f.call(i * 10)); }
     println!();
 
     print!("\nF:");
     for i in 0..=10 { print!("{:3} ", i * 18 + 32); }
     print!("\nC:");
-    for i in 0..=10 { print!("{:3} ", f2c(i * 18 + 32)); }
+    for i in 0..=10 { print!("{:3} ", f2c.call(i * 18 + 32)); }
     println!();
 }
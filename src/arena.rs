@@ -0,0 +1,729 @@
+//! Packing the code of many states into shared executable memory.
+//!
+//! `JitState::emit` maps separate pages for the code and the data of every
+//! state, which wastes most of them when the states are small. A `CodeArena`
+//! instead places the code and data of each state it emits in chunks of
+//! memory that it shares between them, and takes the space back when the
+//! `CompiledCode` is dropped, so that later states can reuse it.
+//! `JitState::into_functions` splits the code of a state into its functions,
+//! whose space is taken back one by one.
+//!
+//! The chunks of an arena made with `CodeArena::new` stay writable and
+//! executable, since other states are emitted into pages that hold live code,
+//! which may be running. One made with `CodeArena::write_xor_execute` is
+//! never both, at the price of making the pages that a state is emitted into
+//! unexecutable meanwhile; see the `mapping` module.
+
+use crate::bindings;
+use crate::code::{protect, CodeBuffer, Protection};
+use crate::mapping::{page_size, round_up};
+use crate::memory::Charge;
+use crate::phase::Emitted;
+use crate::{CompiledCode, DataFlags, EmitError, EmitResult, Error, JitPointer, JitState, JitWord, Result};
+
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// The alignment of every allocation.
+const ALIGN: usize = 16;
+
+/// The default size of the chunks that an arena maps.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Executable memory shared by the states it emits; see the module
+/// documentation.
+///
+/// Clones of an arena share its memory. The memory is unmapped once the
+/// arena and all the code emitted into it are dropped.
+#[derive(Clone, Debug)]
+pub struct CodeArena {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Statistics about the memory of a `CodeArena`, as returned by
+/// `CodeArena::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    /// The number of bytes mapped.
+    pub mapped: usize,
+    /// The number of bytes held by live code and data.
+    pub used: usize,
+    /// The number of live allocations; each emitted state has one for each
+    /// function of its code, and one for its data if it has any.
+    pub allocations: usize,
+    /// The number of separate free ranges.
+    pub free_ranges: usize,
+    /// The size of the largest free range.
+    pub largest_free: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    chunk_size: usize,
+    chunks: Vec<Chunk>,
+    allocations: usize,
+    write_xor_execute: bool,
+    // The pages made writable for the emission under way.
+    unsealed: Vec<Range<usize>>,
+}
+
+/// A mapping, and the ranges of it that are free, as offsets.
+#[derive(Debug)]
+struct Chunk {
+    base: usize,
+    len: usize,
+    free: FreeList,
+}
+
+/// Sorted, non-adjacent free ranges.
+#[derive(Debug, Default)]
+struct FreeList(Vec<Range<usize>>);
+
+/// A range of a chunk held by emitted code.
+#[derive(Debug)]
+struct Block {
+    chunk: usize,
+    range: Range<usize>,
+}
+
+/// The memory of a state emitted by a `CodeArena`, shared by the
+/// `CompiledCode`s that hold its functions. Its data is given back once they
+/// are all dropped.
+#[derive(Debug)]
+struct Emission {
+    arena: Arc<Mutex<Inner>>,
+    chunk: usize,
+    // The offsets in the chunk at which the functions start, followed by the
+    // end of the last one. The first and the last are aligned.
+    bounds: Vec<usize>,
+    // Whether each function is still held by a `Lease`.
+    live: Mutex<Vec<bool>>,
+    data: Option<Block>,
+    // What the code kept for its data, once it is split.
+    #[allow(dead_code)]
+    storage: Option<Box<[u8]>>,
+    #[allow(dead_code)]
+    charge: Option<Charge>,
+}
+
+/// The memory of a `CompiledCode` emitted by a `CodeArena`: the code of some
+/// of the functions of an emission, which is given back when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    emission: Arc<Emission>,
+    functions: Range<usize>,
+}
+
+/// The code buffer of a single emission, which offers whole free ranges, and
+/// gives back what it offered before when it is asked for more.
+struct Reservation<'i> {
+    inner: &'i mut Inner,
+    block: Option<Block>,
+}
+
+impl CodeArena {
+    /// Creates an arena that maps memory in chunks of 64 KiB.
+    pub fn new() -> CodeArena {
+        CodeArena::with_chunk_size(CHUNK_SIZE)
+    }
+
+    /// Creates an arena that maps memory in chunks of `chunk_size` bytes,
+    /// rounded up to whole pages. Code that does not fit in a chunk gets a
+    /// mapping of its own size.
+    pub fn with_chunk_size(chunk_size: usize) -> CodeArena {
+        CodeArena::create(chunk_size, false)
+    }
+
+    /// Creates an arena that maps memory in chunks of `chunk_size` bytes, as
+    /// `with_chunk_size` does, and never makes it writable and executable at
+    /// once.
+    ///
+    /// The pages that a state is emitted into are writable, and no longer
+    /// executable, until `emit` returns, and so are the pages of code that
+    /// `CompiledCode::patch` changes. Other code on those pages must not run
+    /// meanwhile. `patch` holds the arena, so it must not emit into it.
+    pub fn write_xor_execute(chunk_size: usize) -> CodeArena {
+        CodeArena::create(chunk_size, true)
+    }
+
+    fn create(chunk_size: usize, write_xor_execute: bool) -> CodeArena {
+        let inner = Inner {
+            chunk_size: round_up(chunk_size, page_size()),
+            chunks: Vec::new(),
+            allocations: 0,
+            write_xor_execute,
+            unsealed: Vec::new(),
+        };
+        CodeArena { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Emits the machine code for `js` into this arena, along with its
+    /// constants and notes, as `JitState::emit` does. The flags given to
+    /// `JitState::data` are kept, and so is the storage given to it, which
+    /// then holds the data instead of the arena.
+    ///
+    /// Fails if memory cannot be mapped, or its protection changed; the error
+    /// gives the state back unless the code was already emitted.
+    pub fn emit<'a>(&self, js: JitState<'a>) -> EmitResult<'a> {
+        let mut inner = self.inner.lock().unwrap();
+        let placed = inner.place(js);
+        let sealed = inner.seal();
+        // The code given back below locks the arena.
+        drop(inner);
+        let (mut js, code, data) = placed?;
+
+        let base = js.code_addr();
+        let offset = |addr: usize| code.range.start + (addr - usize::from(base));
+        let mut bounds = vec![code.range.start];
+        bounds.extend(
+            js.notes()
+                .functions()
+                .iter()
+                .map(|f| offset(f.start))
+                .filter(|&at| at > code.range.start && at < code.range.end),
+        );
+        bounds.push(code.range.end);
+        bounds.dedup();
+
+        let functions = bounds.len() - 1;
+        self.inner.lock().unwrap().allocations += functions - 1;
+        let emission = Emission {
+            arena: Arc::clone(&self.inner),
+            chunk: code.chunk,
+            bounds,
+            live: Mutex::new(vec![true; functions]),
+            data,
+            storage: None,
+            charge: None,
+        };
+        js.phase.code.lease = Some(Lease { emission: Arc::new(emission), functions: 0..functions });
+        sealed.map_err(EmitError::after)?;
+        Ok(js)
+    }
+
+    /// Returns statistics about the memory of this arena.
+    pub fn stats(&self) -> ArenaStats {
+        let inner = self.inner.lock().unwrap();
+        let mut stats = ArenaStats { allocations: inner.allocations, ..ArenaStats::default() };
+        for chunk in &inner.chunks {
+            let free: usize = chunk.free.0.iter().map(|r| r.len()).sum();
+            stats.mapped += chunk.len;
+            stats.used += chunk.len - free;
+            stats.free_ranges += chunk.free.0.len();
+            stats.largest_free = chunk.free.0.iter().map(|r| r.len()).fold(stats.largest_free, usize::max);
+        }
+        stats
+    }
+}
+
+impl Default for CodeArena {
+    fn default() -> CodeArena {
+        CodeArena::new()
+    }
+}
+
+impl ArenaStats {
+    /// Returns the share of the free memory that lies outside of the largest
+    /// free range, from 0 when the free memory is in one piece to nearly 1
+    /// when it is scattered in small pieces.
+    pub fn fragmentation(&self) -> f64 {
+        let free = self.mapped - self.used;
+        if free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f64 / free as f64
+    }
+}
+
+impl<'a> JitState<'a, Emitted> {
+    /// Splits code emitted by a `CodeArena` into a `CompiledCode` for each of
+    /// its functions, from one `JitState::name` to the next, ordered by
+    /// address. Each gives back the memory of its function when it is
+    /// dropped, and the last of them the memory of the constants. Code
+    /// emitted otherwise is returned whole.
+    ///
+    /// # Safety
+    /// No function may jump to or call another once the `CompiledCode` of
+    /// the other is dropped.
+    pub unsafe fn into_functions(mut self) -> Vec<CompiledCode> {
+        let mut lease = match self.phase.code.lease.take() {
+            Some(lease) => lease,
+            None => return vec![self.into_code()],
+        };
+        let whole = &mut self.phase.code;
+        // The functions are registered on their own instead.
+        whole.withdraw();
+
+        let emission = Arc::get_mut(&mut lease.emission).expect("emitted code is split once");
+        let chunk = emission.arena.lock().unwrap().chunks[emission.chunk].base;
+        let ranges: Vec<_> = emission.bounds.windows(2).map(|b| chunk + b[0]..chunk + b[1]).collect();
+        let charges: Vec<_> = ranges.iter().map(|r| whole.charge.as_mut().map(|c| c.split_code(r.len()))).collect();
+        emission.storage = whole.storage.take();
+        emission.charge = whole.charge.take();
+
+        // The functions now hold the memory, and `lease` none of it.
+        let state = *self.raw();
+        let functions = ranges
+            .into_iter()
+            .zip(charges)
+            .enumerate()
+            .map(|(i, (range, charge))| {
+                let held = Lease { emission: Arc::clone(&lease.emission), functions: i..i + 1 };
+                self.phase.code.part(state, range, held, charge)
+            })
+            .collect();
+        lease.functions = 0..0;
+        functions
+    }
+}
+
+/// A state emitted by `Inner::place`, with the blocks holding its code and
+/// data.
+type Placed<'a> = (JitState<'a, Emitted>, Block, Option<Block>);
+
+impl Inner {
+    /// Emits `js` into this arena, returning the blocks that its code and
+    /// data were placed in, or giving them back on failure.
+    fn place<'a>(&mut self, js: JitState<'a>) -> std::result::Result<Placed<'a>, EmitError<'a>> {
+        unsafe { bindings::lgsys_realize(*js.raw()) };
+        let flags = DataFlags::from_bits(unsafe { bindings::lgsys_data_flags(*js.raw()) });
+        // Data placed in storage of the caller's stays there.
+        let data = if js.storage.is_some() {
+            None
+        } else {
+            let (mut data_size, mut note_size) = (0, 0);
+            unsafe { bindings::lgsys_data_sizes(*js.raw(), &mut data_size, &mut note_size) };
+            let data_len = (data_size + note_size) as usize;
+            if data_len == 0 {
+                // There are no constants or notes to keep.
+                let flags = flags | DataFlags::DISABLE_DATA | DataFlags::DISABLE_NOTE;
+                unsafe { bindings::_jit_set_data(*js.raw(), std::ptr::null_mut(), 0, flags.bits()) };
+                None
+            } else {
+                let block = match self.allocate(data_len, false) {
+                    Some(block) => block,
+                    None => return Err(EmitError::new(Error::CodeBufferFull { size: data_len }, js)),
+                };
+                if let Err(e) = self.unseal(&block) {
+                    self.free(block);
+                    return Err(EmitError::new(e, js));
+                }
+                let at = self.address(&block) as JitPointer;
+                unsafe { bindings::_jit_set_data(*js.raw(), at, block.range.len() as JitWord, flags.bits()) };
+                Some(block)
+            }
+        };
+        let restore = js.storage.is_none();
+
+        // Start from the first free range, however small, and let lightning
+        // ask for more, so that the space of freed code is reused.
+        let mut reservation = Reservation { inner: &mut *self, block: None };
+        let emitted = js.emit_sized(&mut reservation, ALIGN);
+        let code = reservation.block.take();
+        let mut js = match emitted {
+            Ok(js) => js,
+            Err(e) => {
+                for block in code.into_iter().chain(data) {
+                    self.free(block);
+                }
+                // The state given back maps its data itself again, as it
+                // would have without the arena.
+                if let (true, Some(js)) = (restore, e.state.as_ref()) {
+                    unsafe { bindings::lgsys_forget_data(*js.raw(), flags.bits()) };
+                }
+                return Err(e);
+            }
+        };
+
+        // Give back what the code did not use.
+        let mut code = code.expect("emitted code has a block");
+        let used = round_up(js.code_bytes().len(), ALIGN);
+        if used < code.range.len() {
+            let tail = code.range.start + used..code.range.end;
+            code.range.end = tail.start;
+            self.chunks[code.chunk].free.give(tail);
+            js.phase.code.trim_code(used);
+        }
+        if self.write_xor_execute {
+            js.phase.code.set_protection(Protection::Flip);
+        }
+        Ok((js, code, data))
+    }
+
+    /// Takes `size` bytes from the first chunk with room for them, mapping a
+    /// new chunk if none has any. With `whole`, takes all of the free range
+    /// they are found in. Returns `None` if mapping fails.
+    fn allocate(&mut self, size: usize, whole: bool) -> Option<Block> {
+        let size = round_up(size.max(1), ALIGN);
+        let take = |free: &mut FreeList| if whole { free.take_whole(size) } else { free.take(size) };
+        let found = self
+            .chunks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, chunk)| take(&mut chunk.free).map(|range| Block { chunk: i, range }));
+        let block = match found {
+            Some(block) => block,
+            None => {
+                let len = round_up(size, page_size()).max(self.chunk_size);
+                let base = unsafe {
+                    if self.write_xor_execute {
+                        bindings::lgsys_map_writable(len as JitWord)
+                    } else {
+                        bindings::lgsys_map_code(len as JitWord)
+                    }
+                };
+                if base.is_null() {
+                    return None;
+                }
+                let mut chunk = Chunk { base: base as usize, len, free: FreeList::new(len) };
+                let range = take(&mut chunk.free).expect("new chunk fits");
+                self.chunks.push(chunk);
+                Block { chunk: self.chunks.len() - 1, range }
+            }
+        };
+        self.allocations += 1;
+        Some(block)
+    }
+
+    fn free(&mut self, block: Block) {
+        self.chunks[block.chunk].free.give(block.range);
+        self.allocations -= 1;
+    }
+
+    fn address(&self, block: &Block) -> usize {
+        self.chunks[block.chunk].base + block.range.start
+    }
+
+    /// Makes the pages of `block` writable until `seal` is called, if this
+    /// arena is never writable and executable at once.
+    fn unseal(&mut self, block: &Block) -> Result<()> {
+        if !self.write_xor_execute {
+            return Ok(());
+        }
+        let at = self.address(block);
+        protect(at as JitPointer, block.range.len() as JitWord, false)?;
+        self.unsealed.push(at..at + block.range.len());
+        Ok(())
+    }
+
+    /// Makes the pages made writable by `unseal` executable again.
+    fn seal(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for pages in self.unsealed.drain(..) {
+            result = result.and(protect(pages.start as JitPointer, pages.len() as JitWord, true));
+        }
+        result
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for chunk in &self.chunks {
+            unsafe { bindings::lgsys_unmap(chunk.base as JitPointer, chunk.len as JitWord) };
+        }
+    }
+}
+
+impl FreeList {
+    /// Creates a list with the single range `0..len`.
+    fn new(len: usize) -> FreeList {
+        FreeList(std::iter::once(0..len).collect())
+    }
+
+    /// Takes `size` bytes from the first range large enough.
+    fn take(&mut self, size: usize) -> Option<Range<usize>> {
+        let i = self.0.iter().position(|r| r.len() >= size)?;
+        let start = self.0[i].start;
+        self.0[i].start += size;
+        if self.0[i].start == self.0[i].end {
+            self.0.remove(i);
+        }
+        Some(start..start + size)
+    }
+
+    /// Takes the whole of the first range of at least `size` bytes.
+    fn take_whole(&mut self, size: usize) -> Option<Range<usize>> {
+        let i = self.0.iter().position(|r| r.len() >= size)?;
+        Some(self.0.remove(i))
+    }
+
+    /// Gives `range` back, merging it with its neighbours.
+    fn give(&mut self, range: Range<usize>) {
+        let i = self.0.iter().position(|r| r.start > range.start).unwrap_or(self.0.len());
+        self.0.insert(i, range);
+        if i + 1 < self.0.len() && self.0[i].end == self.0[i + 1].start {
+            self.0[i].end = self.0.remove(i + 1).end;
+        }
+        if i > 0 && self.0[i - 1].end == self.0[i].start {
+            self.0[i - 1].end = self.0.remove(i).end;
+        }
+    }
+}
+
+impl Emission {
+    /// Returns the part of the code of function `i` that no live function
+    /// shares: its aligned range, without the ends it shares with the live
+    /// functions around it.
+    fn unshared(&self, live: &[bool], i: usize) -> Range<usize> {
+        let bounds = &self.bounds;
+        let before = live[..i].iter().rposition(|&l| l).map_or(bounds[0], |j| round_up(bounds[j + 1], ALIGN));
+        let after = live[i + 1..]
+            .iter()
+            .position(|&l| l)
+            .map_or(bounds[live.len()], |j| bounds[i + 1 + j] & !(ALIGN - 1));
+        let start = (bounds[i] & !(ALIGN - 1)).max(before);
+        let end = round_up(bounds[i + 1], ALIGN).min(after);
+        start..end.max(start)
+    }
+}
+
+impl Drop for Emission {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            self.arena.lock().unwrap().free(data);
+        }
+    }
+}
+
+impl Lease {
+    /// Locks the arena, so that it changes the protection of no pages until
+    /// the guard is dropped.
+    pub(crate) fn lock(&self) -> impl Sized + '_ {
+        self.emission.arena.lock().unwrap()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let emission = &self.emission;
+        let mut inner = emission.arena.lock().unwrap();
+        let mut live = emission.live.lock().unwrap();
+        for i in self.functions.clone() {
+            live[i] = false;
+            let range = emission.unshared(&live, i);
+            if !range.is_empty() {
+                inner.chunks[emission.chunk].free.give(range);
+            }
+            inner.allocations -= 1;
+        }
+    }
+}
+
+// The chunks are mapped writable and executable, or made writable for the
+// emission by `unseal`, and a block stays out of the free lists until the
+// `Lease` of its code is dropped.
+unsafe impl CodeBuffer for Reservation<'_> {
+    fn reserve(&mut self, size: usize) -> Option<&mut [u8]> {
+        if let Some(block) = self.block.take() {
+            self.inner.free(block);
+        }
+        let block = self.inner.allocate(size, true)?;
+        if self.inner.unseal(&block).is_err() {
+            self.inner.free(block);
+            return None;
+        }
+        let at = self.inner.address(&block) as *mut u8;
+        let len = block.range.len();
+        self.block = Some(block);
+        Some(unsafe { std::slice::from_raw_parts_mut(at, len) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Jit, JitState, JitWord};
+
+    #[test]
+    fn free_list() {
+        let mut free = FreeList::new(100);
+        let (a, b, c) = (free.take(10).unwrap(), free.take(20).unwrap(), free.take(30).unwrap());
+        assert_eq!((a.clone(), b.clone(), c.clone()), (0..10, 10..30, 30..60));
+        assert_eq!(free.take(50), None);
+        assert_eq!(free.take_whole(40), Some(60..100));
+        free.give(60..100);
+
+        free.give(a);
+        free.give(c);
+        assert_eq!(free.0, vec![0..10, 30..100]);
+        assert_eq!(free.take(10), Some(0..10));
+        free.give(0..10);
+        free.give(b);
+        assert_eq!(free.0, vec![0..100]);
+    }
+
+    fn constant(arena: &CodeArena, jit: &mut Jit, value: JitWord) -> crate::CompiledCode<extern "C" fn() -> JitWord> {
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(value);
+        js.epilog();
+        unsafe { arena.emit(js).unwrap().into_code().cast() }
+    }
+
+    #[test]
+    fn trims_code() {
+        let arena = CodeArena::new();
+        let mut jit = Jit::new();
+        let first = constant(&arena, &mut jit, 1);
+        let second = constant(&arena, &mut jit, 2);
+        assert_eq!(arena.stats().mapped, CHUNK_SIZE);
+
+        let range = |code: &crate::CompiledCode<_>| {
+            let start = usize::from(code.code_addr());
+            start..start + code.code_size()
+        };
+        let (a, b) = (range(&first), range(&second));
+        assert!(a.end <= b.start || b.end <= a.start);
+        assert_eq!(first.code_size(), round_up(first.code_bytes().len(), ALIGN));
+        assert!(!first.contains(second.entry_address()));
+        assert!(!second.contains(first.entry_address()));
+        let symbol = crate::registry::lookup(second.entry_address()).unwrap();
+        assert_eq!(symbol.code, second.entry_address());
+        assert!(symbol.end as usize <= b.end);
+        assert_eq!(jit.usage().code, first.code_size() + second.code_size());
+    }
+
+    #[test]
+    fn packs_and_reuses() {
+        let arena = CodeArena::new();
+        let mut jit = Jit::new();
+
+        let mut functions: Vec<_> = (0..100).map(|i| constant(&arena, &mut jit, i)).collect();
+        for (i, f) in functions.iter().enumerate() {
+            assert_eq!(f.call(), i as JitWord);
+        }
+        let stats = arena.stats();
+        assert_eq!(stats.allocations, 100);
+        assert!(stats.mapped <= CHUNK_SIZE, "{:?}", stats);
+
+        // Freeing every other function fragments the free space.
+        let freed = functions[10].entry_address();
+        let kept: Vec<_> = functions.drain(..).enumerate().filter(|(i, _)| i % 2 == 1).map(|(_, f)| f).collect();
+        let stats = arena.stats();
+        assert_eq!(stats.allocations, 50);
+        assert!(stats.free_ranges > 1 && stats.fragmentation() > 0.0, "{:?}", stats);
+
+        // The first hole is reused.
+        let again = constant(&arena, &mut jit, 7);
+        assert!(again.entry_address() <= freed);
+        assert_eq!(again.call(), 7);
+
+        drop(kept);
+        drop(again);
+        let stats = arena.stats();
+        assert_eq!((stats.allocations, stats.used, stats.free_ranges), (0, 0, 1));
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn keeps_constants_and_notes() {
+        let arena = CodeArena::new();
+        let mut jit = Jit::new();
+        let js = arena.emit(half(&mut jit)).unwrap();
+        assert_eq!(js.notes().next().unwrap().1.name.as_deref(), Some("arena_half"));
+        let half = unsafe { js.into_code().cast::<extern "C" fn() -> f64>() };
+        assert_eq!(half.call(), 0.5);
+        assert_eq!(arena.stats().allocations, 2);
+    }
+
+    fn half<'j>(jit: &'j mut Jit) -> JitState<'j> {
+        let mut js = jit.new_state();
+        js.name(Some("arena_half")).unwrap();
+        js.note(Some("arena.rs"), 1).unwrap();
+        js.prolog();
+        js.movi_d(crate::FReg::F(0), 0.5);
+        js.retr_d(crate::FReg::F(0));
+        js.epilog();
+        js
+    }
+
+    #[test]
+    fn keeps_data_configuration() {
+        let arena = CodeArena::new();
+        let mut jit = Jit::new();
+
+        let mut js = half(&mut jit);
+        js.data().notes(false).apply().unwrap();
+        let js = arena.emit(js).unwrap();
+        assert_eq!(js.notes().count(), 0);
+        assert!(js.note_bytes().is_empty());
+        assert!(!js.data_bytes().is_empty());
+        let code = unsafe { js.into_code().cast::<extern "C" fn() -> f64>() };
+        assert_eq!(code.call(), 0.5);
+        drop(code);
+
+        let storage = vec![0; 4096];
+        let start = storage.as_ptr() as usize;
+        let mut js = half(&mut jit);
+        js.data().storage(storage).apply().unwrap();
+        let code = unsafe { arena.emit(js).unwrap().into_code().cast::<extern "C" fn() -> f64>() };
+        assert_eq!(code.call(), 0.5);
+        assert_eq!(code.data_bytes().as_ptr() as usize, start);
+        assert_eq!(arena.stats().allocations, 1);
+    }
+
+    #[test]
+    fn frees_functions() {
+        let arena = CodeArena::new();
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        for &(name, value) in &[("arena_one", 1.0), ("arena_two", 2.0)] {
+            js.name(Some(name)).unwrap();
+            js.prolog();
+            js.movi_d(crate::FReg::F(0), value);
+            js.retr_d(crate::FReg::F(0));
+            js.epilog();
+        }
+        let js = arena.emit(js).unwrap();
+        let whole = js.code_size();
+        let mut functions = unsafe { js.into_functions() };
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].code_size() + functions[1].code_size(), whole);
+        assert_eq!(arena.stats().allocations, 3);
+
+        let two = unsafe { functions.pop().unwrap().cast::<extern "C" fn() -> f64>() };
+        let one = unsafe { functions.pop().unwrap().cast::<extern "C" fn() -> f64>() };
+        assert!(one.entry_address() < two.entry_address());
+        let symbol = crate::registry::lookup(two.entry_address()).unwrap();
+        assert_eq!((symbol.name.as_deref(), symbol.code), (Some("arena_two"), two.entry_address()));
+        assert_eq!(one.call(), 1.0);
+
+        // The first function goes, and its space is reused, while the second
+        // one still runs.
+        let (freed, used) = (one.entry_address(), arena.stats().used);
+        drop(one);
+        let stats = arena.stats();
+        assert_eq!((stats.allocations, stats.free_ranges), (2, 2));
+        assert!(stats.used < used, "{:?}", stats);
+        assert!(crate::registry::lookup(freed).is_none());
+        let mut js = half(&mut jit);
+        js.data().notes(false).apply().unwrap();
+        let again = unsafe { arena.emit(js).unwrap().into_code().cast::<extern "C" fn() -> f64>() };
+        assert_eq!(again.data_bytes().as_ptr() as JitPointer, freed);
+        assert_eq!((two.call(), again.call()), (2.0, 0.5));
+
+        // The constants go with the last function.
+        drop(two);
+        drop(again);
+        let stats = arena.stats();
+        assert_eq!((stats.allocations, stats.used), (0, 0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn write_xor_execute() {
+        use crate::mapping::tests::permissions;
+
+        let arena = CodeArena::write_xor_execute(CHUNK_SIZE);
+        let mut jit = Jit::new();
+        let mut first = constant(&arena, &mut jit, 1);
+        let second = constant(&arena, &mut jit, 2);
+        let page = usize::from(first.code_addr());
+        assert_eq!(permissions(page), "r-x");
+        assert_eq!((first.call(), second.call()), (1, 2));
+
+        first.patch(|_| assert_eq!(permissions(page), "rw-")).unwrap();
+        assert_eq!(permissions(page), "r-x");
+        assert_eq!((first.call(), second.call()), (1, 2));
+    }
+}
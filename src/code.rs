@@ -0,0 +1,596 @@
+use crate::arena;
+use crate::bindings;
+#[cfg(feature = "gdb")]
+use crate::gdb;
+use crate::mapping::{page_size, round_up};
+use crate::memory;
+use crate::notes::Notes;
+use crate::registry;
+use crate::types::StateId;
+#[cfg(feature = "unwind")]
+use crate::unwind;
+use crate::{Error, JitFnPtr, JitPointer, JitWord, Result};
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Add, Range, Sub};
+use std::os::raw::c_int;
+
+/// Executable code produced by `JitState::emit`.
+///
+/// A `CompiledCode` owns the memory that lightning mapped for the emitted
+/// instructions and their constant data, so it remains valid after the
+/// originating `JitState` is cleared or dropped. The memory is released when
+/// the `CompiledCode` is dropped.
+///
+/// For as long as it lives, its functions can be found by address with
+/// `registry::lookup`. With the `gdb` feature, the code is also registered
+/// with GDB's JIT interface, along with the functions named by
+/// `JitState::name` and the lines recorded by `JitState::note`, so that
+/// breakpoints and backtraces work in it. With the `unwind` feature, the
+/// unwind information of its functions is registered; see the `unwind`
+/// module.
+///
+/// `F` is the type of the entry point (the first instruction emitted), which
+/// is run with `call`. The function pointer itself is only handed out by the
+/// unsafe `as_ptr`, since nothing would stop it from outliving the code.
+/// `function` and `at` return a `JitFn` instead, which borrows the code, so
+/// that it cannot be called once the code is dropped:
+///
+/// ```compile_fail,E0505
+/// # use lightning_sys::{Jit, JitWord};
+/// let mut jit = Jit::new();
+/// let mut js = jit.new_state();
+/// js.prolog();
+/// js.reti(1);
+/// js.epilog();
+/// let code = unsafe { js.cast_emit::<extern "C" fn() -> JitWord>() }.unwrap().into_code();
+/// let f = code.function();
+/// drop(code);
+/// f.call();
+/// ```
+#[derive(Debug)]
+pub struct CompiledCode<F = JitPointer> {
+    code: bindings::lgsys_buffer,
+    // The number of bytes of instructions at the start of `code`.
+    code_len: usize,
+    data: bindings::lgsys_buffer,
+    // The number of bytes of constants at the start of `data`, and of notes
+    // after them.
+    data_len: usize,
+    note_len: usize,
+    pub(crate) entry: F,
+    state: StateId,
+    #[cfg(feature = "gdb")]
+    pub(crate) debug: Option<gdb::Registration>,
+    registry: Option<registry::Registration>,
+    #[cfg(feature = "unwind")]
+    pub(crate) unwind: Option<unwind::Registration>,
+    // The memory of code emitted by a `CodeArena`, which must outlive the
+    // registrations above.
+    pub(crate) lease: Option<arena::Lease>,
+    // Storage given to `DataBuilder::storage`, which holds the data.
+    pub(crate) storage: Option<Box<[u8]>>,
+    protection: Protection,
+    // The memory counted against the `Jit` that emitted the code.
+    pub(crate) charge: Option<memory::Charge>,
+}
+
+/// How the code can be made writable to patch it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protection {
+    /// The code is always writable, as in a `CodeBuffer` or a `CodeArena`.
+    Writable,
+    /// The code is made writable, and no longer executable, while it is
+    /// patched.
+    Flip,
+    /// The code is patched through a writable mapping of the same memory at
+    /// this address.
+    Alias(usize),
+}
+
+/// Memory that `JitState::emit_into` places code in, such as an mmap'd
+/// region, a shared memory segment or a slot of a pre-allocated pool.
+///
+/// The trait is unsafe to implement because the code is run from the memory
+/// it hands out, which nothing else can check.
+///
+/// # Safety
+/// The code is emitted straight into the memory, and the resulting
+/// `CompiledCode` does not borrow the buffer, so an implementation must make
+/// sure that every region it returns from `reserve`:
+///
+/// * is writable now, and executable by the time the code is run, which the
+///   implementation arranges, for instance by mapping it writable and
+///   executable, or by making it executable once `emit_into` returns;
+/// * is at least as long as the slice it returns;
+/// * stays valid, and is not handed out again, for as long as the
+///   `CompiledCode` emitted into it lives, even after the buffer itself is
+///   dropped.
+///
+/// A region that `emit_into` asks to replace with a larger one may be reused.
+pub unsafe trait CodeBuffer {
+    /// Returns memory of at least `size` bytes, or `None` if the buffer cannot
+    /// provide that much. `size` is lightning's estimate at first, and grows
+    /// if the code does not fit.
+    fn reserve(&mut self, size: usize) -> Option<&mut [u8]>;
+}
+
+/// An address within emitted code.
+///
+/// Unlike a `JitPointer`, a `CodeAddr` can be hashed, ordered and offset, and
+/// the distance between two of them is a `usize`, so code can be measured and
+/// compared without casting pointers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CodeAddr(usize);
+
+/// A function pointer into a `CompiledCode`, which cannot outlive it; the
+/// function is run with `call`.
+#[derive(Debug, Clone, Copy)]
+pub struct JitFn<'c, F> {
+    pub(crate) func: F,
+    phantom: PhantomData<&'c ()>,
+}
+
+impl CompiledCode {
+    /// Takes ownership of the buffers that lightning mapped while emitting
+    /// `state`, whose id is `id`.
+    pub(crate) unsafe fn take(state: *mut bindings::jit_state_t, id: StateId, entry: JitPointer) -> CompiledCode {
+        let mut code = std::mem::MaybeUninit::uninit();
+        let mut data = std::mem::MaybeUninit::uninit();
+        bindings::lgsys_take_buffers(state, code.as_mut_ptr(), data.as_mut_ptr());
+        let code = code.assume_init();
+        let mut code_len = 0;
+        bindings::_jit_get_code(state, &mut code_len);
+        let (mut data_len, mut note_len) = (0, 0);
+        bindings::lgsys_data_sizes(state, &mut data_len, &mut note_len);
+
+        let range = code.ptr as usize..code.ptr as usize + code_len as usize;
+
+        CompiledCode {
+            #[cfg(feature = "unwind")]
+            unwind: unwind::Registration::new(state, range.clone()),
+            #[cfg(feature = "gdb")]
+            debug: gdb::Registration::new(Notes::new(state), range.clone()),
+            registry: Some(register(state, id, range)),
+            code,
+            code_len: code_len as usize,
+            data: data.assume_init(),
+            data_len: data_len as usize,
+            note_len: note_len as usize,
+            entry,
+            state: id,
+            lease: None,
+            storage: None,
+            protection: if code.owned != 0 { Protection::Flip } else { Protection::Writable },
+            charge: None,
+        }
+    }
+
+    /// Makes a `CompiledCode` of the functions at `range` of this code, which
+    /// `state` emitted, holding `lease` and `charge` for their memory. It
+    /// shares the data of this code, which must outlive it.
+    pub(crate) unsafe fn part(
+        &self,
+        state: *mut bindings::jit_state_t,
+        range: Range<usize>,
+        lease: arena::Lease,
+        charge: Option<memory::Charge>,
+    ) -> CompiledCode {
+        let instructions = range.start..range.end.min(self.code.ptr as usize + self.code_len);
+        let code = bindings::lgsys_buffer { ptr: range.start as JitPointer, length: range.len() as JitWord, owned: 0 };
+        let data = bindings::lgsys_buffer { ptr: self.data.ptr, length: self.data.length, owned: 0 };
+
+        CompiledCode {
+            #[cfg(feature = "unwind")]
+            unwind: unwind::Registration::new(state, range.clone()),
+            #[cfg(feature = "gdb")]
+            debug: gdb::Registration::new(Notes::new(state), range.clone()),
+            registry: Some(register(state, self.state, range.clone())),
+            code,
+            code_len: instructions.len(),
+            data,
+            data_len: self.data_len,
+            note_len: self.note_len,
+            entry: range.start as JitPointer,
+            state: self.state,
+            lease: Some(lease),
+            storage: None,
+            protection: self.protection,
+            charge,
+        }
+    }
+
+    /// Reinterprets the entry point as a function pointer of type `T`.
+    ///
+    /// # Safety
+    /// `T` must match the signature of the emitted code.
+    pub unsafe fn cast<T: JitFnPtr>(mut self) -> CompiledCode<T> {
+        let entry = T::from_pointer(self.entry);
+        let out = CompiledCode {
+            code: self.code,
+            code_len: self.code_len,
+            data: self.data,
+            data_len: self.data_len,
+            note_len: self.note_len,
+            entry,
+            state: self.state,
+            #[cfg(feature = "gdb")]
+            debug: self.debug.take(),
+            registry: self.registry.take(),
+            lease: self.lease.take(),
+            storage: self.storage.take(),
+            protection: self.protection,
+            charge: self.charge.take(),
+            #[cfg(feature = "unwind")]
+            unwind: self.unwind.take(),
+        };
+        std::mem::forget(self);
+        out
+    }
+}
+
+/// Adds the functions of `state` within `code` to the registry, or `code` as
+/// a single anonymous function if it has none.
+unsafe fn register(state: *mut bindings::jit_state_t, id: StateId, code: Range<usize>) -> registry::Registration {
+    let functions = Notes::new(state).functions();
+    let mut ranges: Vec<_> = functions
+        .iter()
+        .filter(|f| code.contains(&f.start))
+        .map(|f| (f.start, f.size.min(code.end - f.start), f.name.as_deref()))
+        .collect();
+    if ranges.is_empty() {
+        ranges.push((code.start, code.len(), None));
+    }
+    registry::Registration::new(code.start, id, ranges)
+}
+
+impl<F: Copy> CompiledCode<F> {
+    /// Returns the entry point as a function pointer.
+    ///
+    /// # Safety
+    /// The pointer must not be called once this `CompiledCode` is dropped.
+    pub unsafe fn as_ptr(&self) -> F {
+        self.entry
+    }
+
+    /// Returns the entry point as a function that borrows this code.
+    pub fn function(&self) -> JitFn<'_, F> {
+        JitFn {
+            func: self.entry,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'c, F: Copy> JitFn<'c, F> {
+    /// Returns the function pointer.
+    ///
+    /// # Safety
+    /// The pointer must not be called once the `CompiledCode` it points into
+    /// is dropped.
+    pub unsafe fn as_ptr(&self) -> F {
+        self.func
+    }
+}
+
+impl<F> CompiledCode<F> {
+    /// Returns the address of the first emitted instruction.
+    pub fn entry_address(&self) -> JitPointer {
+        self.code.ptr
+    }
+
+    /// Returns the address of the first emitted instruction.
+    pub fn code_addr(&self) -> CodeAddr {
+        CodeAddr::from(self.code.ptr)
+    }
+
+    /// Returns the id of the state that emitted the code, as recorded in its
+    /// `registry::Symbol`s.
+    pub fn state_id(&self) -> StateId {
+        self.state
+    }
+
+    /// Returns the size in bytes of the mapping holding the code.
+    pub fn code_size(&self) -> usize {
+        self.code.length as usize
+    }
+
+    /// Returns the emitted instructions, which may be followed by unused
+    /// space in the mapping.
+    pub fn code_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.code.ptr as *const u8, self.code_len) }
+    }
+
+    /// Returns whether `addr` points into the code of this `CompiledCode`.
+    pub fn contains(&self, addr: JitPointer) -> bool {
+        let start = self.code.ptr as usize;
+        let addr = addr as usize;
+        addr >= start && addr < start + self.code_size()
+    }
+
+    /// Returns the constants that the code loads from the data buffer.
+    pub fn data_bytes(&self) -> &[u8] {
+        self.data_range(0, self.data_len)
+    }
+
+    /// Returns the notes kept in the data buffer after the constants.
+    pub fn note_bytes(&self) -> &[u8] {
+        self.data_range(self.data_len, self.note_len)
+    }
+
+    fn data_range(&self, start: usize, len: usize) -> &[u8] {
+        let available = self.data.length as usize;
+        if self.data.ptr.is_null() || start + len > available {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts((self.data.ptr as *const u8).add(start), len) }
+    }
+
+    /// Runs `patch` on the emitted instructions while they are writable, for
+    /// changes such as `JitState::patch_abs` makes before emission, and makes
+    /// them executable again afterwards.
+    ///
+    /// Unless the code was emitted into memory that is always writable, or
+    /// with `CodeMapping::DualMapped`, it is not executable while `patch`
+    /// runs, nor is other code on the same pages, so no thread may run them
+    /// meanwhile. It is made executable again
+    /// even if `patch` panics. Fails if the protection of the code cannot be
+    /// changed.
+    pub fn patch<R>(&mut self, patch: impl FnOnce(&mut [u8]) -> R) -> Result<R> {
+        // Keep an arena from changing the protection of the pages meanwhile.
+        let _arena = self.lease.as_ref().filter(|_| self.protection == Protection::Flip).map(arena::Lease::lock);
+        let (ptr, length) = (self.code.ptr, self.code.length);
+        let writable = match self.protection {
+            Protection::Writable => ptr,
+            Protection::Flip => {
+                protect(ptr, length, false)?;
+                ptr
+            }
+            Protection::Alias(alias) => alias as JitPointer,
+        };
+
+        let mut restore = Restore { ptr, length, flip: self.protection == Protection::Flip, done: false };
+        let result = patch(unsafe { std::slice::from_raw_parts_mut(writable as *mut u8, self.code_len) });
+        restore.restore()?;
+        Ok(result)
+    }
+
+    /// Gives up the part of the code's memory after its first `length` bytes,
+    /// which a `CodeArena` hands to other states.
+    pub(crate) fn trim_code(&mut self, length: usize) {
+        debug_assert!(length >= self.code_len && length <= self.code.length as usize);
+        self.code.length = length as JitWord;
+        if let Some(charge) = &mut self.charge {
+            charge.shrink_code(length);
+        }
+    }
+
+    /// Takes ownership of a mapping of the code made by `mapping`, which can
+    /// be patched as `protection` says.
+    pub(crate) fn own_mapping(&mut self, protection: Protection) {
+        self.code.owned = 1;
+        self.protection = protection;
+    }
+
+    /// Makes the code, whose memory is not its own, patched as `protection`
+    /// says.
+    pub(crate) fn set_protection(&mut self, protection: Protection) {
+        self.protection = protection;
+    }
+
+    /// Withdraws the code from GDB, the registry and the unwinder.
+    pub(crate) fn withdraw(&mut self) {
+        #[cfg(feature = "gdb")]
+        {
+            self.debug = None;
+        }
+        self.registry = None;
+        #[cfg(feature = "unwind")]
+        {
+            self.unwind = None;
+        }
+    }
+
+    /// Reinterprets `addr` (typically obtained from `JitState::address`) as a
+    /// function pointer of type `T` that borrows from this `CompiledCode`.
+    ///
+    /// # Safety
+    /// `T` must match the signature of the code at `addr`.
+    pub unsafe fn at<T: JitFnPtr>(&self, addr: JitPointer) -> JitFn<'_, T> {
+        assert!(self.contains(addr), "address {:p} is outside of the compiled code", addr);
+        JitFn {
+            func: T::from_pointer(addr),
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// Makes the pages holding the `length` bytes at `ptr` executable, or
+/// writable.
+pub(crate) fn protect(ptr: JitPointer, length: JitWord, executable: bool) -> Result<()> {
+    let page = page_size();
+    let start = ptr as usize & !(page - 1);
+    let end = round_up(ptr as usize + length as usize, page);
+    match unsafe { bindings::lgsys_protect(start as JitPointer, (end - start) as JitWord, executable as c_int) } {
+        0 => Ok(()),
+        errno => Err(Error::ProtectFailed(errno)),
+    }
+}
+
+/// Makes code patched by `CompiledCode::patch` executable again, and flushes
+/// the instruction cache, when dropped if not before.
+struct Restore {
+    ptr: JitPointer,
+    length: JitWord,
+    // Whether the code was made writable, and no longer executable.
+    flip: bool,
+    done: bool,
+}
+
+impl Restore {
+    fn restore(&mut self) -> Result<()> {
+        self.done = true;
+        if self.flip {
+            protect(self.ptr, self.length, true)
+        } else {
+            unsafe { bindings::lgsys_flush(self.ptr, self.length) };
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if !self.done {
+            // Unwinding from `patch`; there is no one to report a failure to.
+            let _ = self.restore();
+        }
+    }
+}
+
+impl CodeAddr {
+    /// Returns the address as a pointer.
+    pub fn as_ptr(self) -> JitPointer {
+        self.0 as JitPointer
+    }
+
+    /// Returns the distance in bytes from `base` to this address, or `None` if
+    /// this address comes before `base`.
+    pub fn offset_from(self, base: CodeAddr) -> Option<usize> {
+        self.0.checked_sub(base.0)
+    }
+}
+
+impl From<JitPointer> for CodeAddr {
+    fn from(ptr: JitPointer) -> CodeAddr {
+        CodeAddr(ptr as usize)
+    }
+}
+
+impl From<CodeAddr> for usize {
+    fn from(addr: CodeAddr) -> usize {
+        addr.0
+    }
+}
+
+impl Add<usize> for CodeAddr {
+    type Output = CodeAddr;
+
+    fn add(self, offset: usize) -> CodeAddr {
+        CodeAddr(self.0 + offset)
+    }
+}
+
+impl Sub<usize> for CodeAddr {
+    type Output = CodeAddr;
+
+    fn sub(self, offset: usize) -> CodeAddr {
+        CodeAddr(self.0 - offset)
+    }
+}
+
+/// The distance in bytes between two addresses; panics if `base` comes after
+/// `self`.
+impl Sub for CodeAddr {
+    type Output = usize;
+
+    fn sub(self, base: CodeAddr) -> usize {
+        self.offset_from(base).expect("subtracted a later address")
+    }
+}
+
+impl fmt::Display for CodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+impl<F> Drop for CompiledCode<F> {
+    fn drop(&mut self) {
+        // Withdraw the code before it goes away.
+        self.withdraw();
+        unsafe {
+            if let Protection::Alias(alias) = self.protection {
+                bindings::lgsys_unmap(alias as JitPointer, self.code.length);
+            }
+            bindings::lgsys_release_buffer(&mut self.code);
+            bindings::lgsys_release_buffer(&mut self.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CodeAddr;
+    use crate::{Jit, JitWord, Reg};
+
+    #[test]
+    fn outlives_state() {
+        let mut jit = Jit::new();
+        let incr = {
+            let mut js = jit.new_state();
+            js.prolog();
+            let inarg = js.arg::<JitWord>();
+            js.getarg(Reg::R(0), &inarg);
+            js.addi(Reg::R(0), Reg::R(0), 1);
+            js.retr(Reg::R(0));
+            unsafe { js.cast_emit::<extern "C" fn(JitWord) -> JitWord>() }.unwrap().into_code()
+        };
+
+        assert_eq!(incr.call(5), 6);
+        assert_eq!(incr.function().call(6), 7);
+    }
+
+    #[test]
+    fn address_borrows_code() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+
+        let start = js.label();
+        js.prolog();
+        js.reti(7);
+        js.epilog();
+        let second = js.label();
+        js.prolog();
+        js.reti(8);
+        js.epilog();
+
+        let js = js.emit().unwrap();
+        let (start, second) = (js.address(&start), js.address(&second));
+        let code = js.into_code();
+        let first = unsafe { code.at::<extern "C" fn() -> JitWord>(start) };
+        let second = unsafe { code.at::<extern "C" fn() -> JitWord>(second) };
+
+        assert_eq!(first.call(), 7);
+        assert_eq!(second.call(), 8);
+    }
+
+    #[test]
+    fn code_bytes() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(3);
+        js.epilog();
+        let end = js.label();
+
+        let js = js.emit().unwrap();
+        let bytes = js.code_bytes();
+        assert!(!bytes.is_empty() && bytes.len() <= js.code_size());
+        assert_eq!(bytes.as_ptr() as usize, usize::from(js.code_addr()));
+        assert!(CodeAddr::from(js.address(&end)) - js.code_addr() <= bytes.len());
+    }
+
+    #[test]
+    fn address_arithmetic() {
+        let base = CodeAddr::from(0x1000 as crate::JitPointer);
+        let addr = base + 0x20;
+        assert_eq!(addr - base, 0x20);
+        assert_eq!(addr - 0x20, base);
+        assert_eq!(base.offset_from(addr), None);
+        assert!(base < addr);
+        assert_eq!(addr.to_string(), "0x1020");
+        assert_eq!(addr.as_ptr() as usize, 0x1020);
+    }
+}
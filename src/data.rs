@@ -0,0 +1,197 @@
+//! Control over the data buffer, which holds the constants used by the code
+//! and the notes recorded by `JitState::name` and `JitState::note`.
+
+use crate::bindings;
+use crate::{Error, JitPointer, JitState, JitWord, Result};
+
+use std::ops::{BitOr, BitOrAssign};
+
+/// Flags that change what lightning keeps in the data buffer, combined with
+/// `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DataFlags(JitWord);
+
+impl DataFlags {
+    /// Builds constants into the instructions instead of loading them from
+    /// the data buffer (`JIT_DISABLE_DATA`).
+    pub const DISABLE_DATA: DataFlags = DataFlags(1);
+    /// Drops the notes, which saves their memory but leaves `JitState::notes`,
+    /// `lookup` and the debugging integrations with nothing to work with
+    /// (`JIT_DISABLE_NOTE`).
+    pub const DISABLE_NOTE: DataFlags = DataFlags(2);
+
+    /// Returns the set without any flags.
+    pub const fn empty() -> DataFlags {
+        DataFlags(0)
+    }
+
+    /// Returns the flags as lightning's integer.
+    pub const fn bits(self) -> JitWord {
+        self.0
+    }
+
+    /// Returns the flags in lightning's integer `bits`.
+    pub(crate) const fn from_bits(bits: JitWord) -> DataFlags {
+        DataFlags(bits)
+    }
+
+    /// Returns whether every flag of `other` is set in `self`.
+    pub fn contains(self, other: DataFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for DataFlags {
+    type Output = DataFlags;
+
+    fn bitor(self, other: DataFlags) -> DataFlags {
+        DataFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for DataFlags {
+    fn bitor_assign(&mut self, other: DataFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// Configures the data buffer of a state before it is emitted; returned by
+/// `JitState::data`.
+///
+/// Nothing changes until `apply` is called.
+#[derive(Debug)]
+#[must_use]
+pub struct DataBuilder<'j, 'a> {
+    js: &'j mut JitState<'a>,
+    flags: DataFlags,
+    storage: Option<Box<[u8]>>,
+}
+
+impl<'a> JitState<'a> {
+    /// Starts configuring the data buffer of this state.
+    pub fn data(&mut self) -> DataBuilder<'_, 'a> {
+        DataBuilder { js: self, flags: DataFlags::empty(), storage: None }
+    }
+}
+
+impl<'j, 'a> DataBuilder<'j, 'a> {
+    /// Adds `flags`.
+    pub fn flags(mut self, flags: DataFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Keeps the notes if `keep` is true, and drops them otherwise; for
+    /// instance `notes(cfg!(debug_assertions))` keeps them in debug builds
+    /// only.
+    pub fn notes(mut self, keep: bool) -> Self {
+        if keep {
+            self.flags.0 &= !DataFlags::DISABLE_NOTE.0;
+        } else {
+            self.flags |= DataFlags::DISABLE_NOTE;
+        }
+        self
+    }
+
+    /// Places the data in `storage` instead of memory that lightning maps.
+    /// The notes are dropped if they do not fit after the constants. The
+    /// emitted code keeps the storage, and frees it when it is dropped.
+    pub fn storage(mut self, storage: impl Into<Box<[u8]>>) -> Self {
+        self.storage = Some(storage.into());
+        self
+    }
+
+    /// Applies the configuration. This fixes the instructions of the state,
+    /// so it must be the last thing done before emitting it.
+    ///
+    /// Fails if the storage is empty or cannot hold the constants.
+    pub fn apply(self) -> Result<()> {
+        unsafe { bindings::lgsys_realize(*self.js.raw()) };
+
+        let (buf, len) = match self.storage {
+            Some(mut storage) => {
+                let mut data_size = 0;
+                unsafe { bindings::_jit_get_data(*self.js.raw(), &mut data_size, std::ptr::null_mut()) };
+                let needed = if self.flags.contains(DataFlags::DISABLE_DATA) { 0 } else { data_size as usize };
+                if storage.is_empty() || storage.len() < needed {
+                    return Err(Error::InvalidBuffer);
+                }
+                let buf = (storage.as_mut_ptr() as JitPointer, storage.len() as JitWord);
+                // Moving the box leaves its contents where they are.
+                self.js.storage = Some(storage);
+                buf
+            }
+            None => {
+                self.js.storage = None;
+                (std::ptr::null_mut(), 0)
+            }
+        };
+        unsafe { bindings::_jit_set_data(*self.js.raw(), buf, len, self.flags.bits()) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Jit, Reg};
+
+    fn noted<'j>(jit: &'j mut Jit) -> JitState<'j> {
+        let mut js = jit.new_state();
+        js.name(Some("data_answer")).unwrap();
+        js.note(Some("data.rs"), 7).unwrap();
+        js.prolog();
+        js.movi_d(crate::FReg::F(0), 2.5);
+        js.movi(Reg::R(0), 42);
+        js.retr(Reg::R(0));
+        js.epilog();
+        js
+    }
+
+    #[test]
+    fn flags() {
+        let both = DataFlags::DISABLE_DATA | DataFlags::DISABLE_NOTE;
+        assert_eq!(both.bits(), 3);
+        assert!(both.contains(DataFlags::DISABLE_NOTE));
+        assert!(!DataFlags::empty().contains(DataFlags::DISABLE_DATA));
+    }
+
+    #[test]
+    fn drop_notes() {
+        let mut jit = Jit::new();
+        {
+            let mut js = noted(&mut jit);
+            js.data().notes(false).apply().unwrap();
+            let js = js.emit().unwrap();
+            assert!(js.note_bytes().is_empty());
+        }
+
+        let mut js = noted(&mut jit);
+        js.data().notes(true).apply().unwrap();
+        let js = js.emit().unwrap();
+        assert_eq!(js.notes().count(), 1);
+        assert!(!js.note_bytes().is_empty());
+    }
+
+    #[test]
+    fn own_storage() {
+        let storage = vec![0; 4096];
+        let start = storage.as_ptr() as usize;
+
+        let mut jit = Jit::new();
+        let mut js = noted(&mut jit);
+        js.data().storage(storage).apply().unwrap();
+        let js = js.emit().unwrap();
+        let answer = unsafe { js.into_code().cast::<extern "C" fn() -> JitWord>() };
+        assert_eq!(answer.call(), 42);
+        assert_eq!(answer.data_bytes().as_ptr() as usize, start);
+    }
+
+    #[test]
+    fn storage_too_small() {
+        let mut jit = Jit::new();
+        let mut js = noted(&mut jit);
+        let result = js.data().storage(Vec::new()).apply();
+        assert_eq!(result, Err(Error::InvalidBuffer));
+    }
+}
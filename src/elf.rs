@@ -0,0 +1,277 @@
+//! A writer for the small ELF objects that describe emitted code to tools.
+//!
+//! Everything is written in the byte order and word size of the target, since
+//! the objects only ever describe code in the running process.
+
+// Only the `gdb` feature builds whole objects.
+#![cfg_attr(not(feature = "gdb"), allow(dead_code))]
+
+/// The `e_machine` of ELF files for the target.
+#[cfg(target_arch = "x86")]
+pub(crate) const MACHINE: u16 = 3;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+pub(crate) const MACHINE: u16 = 8;
+#[cfg(target_arch = "powerpc")]
+pub(crate) const MACHINE: u16 = 20;
+#[cfg(target_arch = "powerpc64")]
+pub(crate) const MACHINE: u16 = 21;
+#[cfg(target_arch = "s390x")]
+pub(crate) const MACHINE: u16 = 22;
+#[cfg(target_arch = "arm")]
+pub(crate) const MACHINE: u16 = 40;
+#[cfg(target_arch = "x86_64")]
+pub(crate) const MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+pub(crate) const MACHINE: u16 = 183;
+#[cfg(target_arch = "riscv64")]
+pub(crate) const MACHINE: u16 = 243;
+#[cfg(not(any(
+    target_arch = "x86", target_arch = "mips", target_arch = "mips64",
+    target_arch = "powerpc", target_arch = "powerpc64", target_arch = "s390x",
+    target_arch = "arm", target_arch = "x86_64", target_arch = "aarch64",
+    target_arch = "riscv64",
+)))]
+pub(crate) const MACHINE: u16 = 0;
+
+pub(crate) const SHT_PROGBITS: u32 = 1;
+pub(crate) const SHT_SYMTAB: u32 = 2;
+pub(crate) const SHT_STRTAB: u32 = 3;
+pub(crate) const SHT_NOBITS: u32 = 8;
+
+pub(crate) const SHF_ALLOC: usize = 0x2;
+pub(crate) const SHF_EXECINSTR: usize = 0x4;
+
+pub(crate) const STB_LOCAL: u8 = 0;
+pub(crate) const STB_GLOBAL: u8 = 1;
+pub(crate) const STT_FUNC: u8 = 2;
+pub(crate) const STT_FILE: u8 = 4;
+pub(crate) const SHN_ABS: u16 = 0xfff1;
+
+const ET_REL: u16 = 1;
+const WORD: usize = std::mem::size_of::<usize>();
+
+/// Appends values in the byte order of the target to a buffer.
+#[derive(Debug, Default)]
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    /// Writes an address or size of the target's word size.
+    pub(crate) fn word(&mut self, value: usize) {
+        self.buf.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub(crate) fn uleb128(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    pub(crate) fn sleb128(&mut self, mut value: i64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    /// Writes `value` followed by a NUL byte.
+    pub(crate) fn c_str(&mut self, value: &str) {
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Overwrites the `u32` at `at`, for lengths only known after the fact.
+    pub(crate) fn patch_u32(&mut self, at: usize, value: u32) {
+        self.buf[at..at + 4].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    /// Pads to a multiple of `to`, which must be a power of two.
+    fn align(&mut self, to: usize) {
+        while self.buf.len() & (to - 1) != 0 {
+            self.buf.push(0);
+        }
+    }
+}
+
+/// A string table, which starts with an empty string.
+#[derive(Debug)]
+pub(crate) struct StringTable(Writer);
+
+impl StringTable {
+    pub(crate) fn new() -> StringTable {
+        StringTable(Writer { buf: vec![0] })
+    }
+
+    /// Adds `value` and returns its offset.
+    pub(crate) fn add(&mut self, value: &str) -> u32 {
+        let at = self.0.len() as u32;
+        self.0.c_str(value);
+        at
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.0.buf
+    }
+}
+
+/// Writes an entry of a symbol table, whose layout depends on the word size.
+pub(crate) fn symbol(out: &mut Writer, name: u32, info: u8, shndx: u16, value: usize, size: usize) {
+    out.u32(name);
+    if WORD == 8 {
+        out.u8(info);
+        out.u8(0);
+        out.u16(shndx);
+        out.word(value);
+        out.word(size);
+    } else {
+        out.word(value);
+        out.word(size);
+        out.u8(info);
+        out.u8(0);
+        out.u16(shndx);
+    }
+}
+
+/// The size of an entry written by `symbol`.
+#[cfg(target_pointer_width = "64")]
+pub(crate) const SYMBOL_SIZE: usize = 24;
+#[cfg(target_pointer_width = "32")]
+pub(crate) const SYMBOL_SIZE: usize = 16;
+
+/// A section of an object built by `object`.
+#[derive(Debug)]
+pub(crate) struct Section {
+    pub(crate) name: &'static str,
+    pub(crate) kind: u32,
+    pub(crate) flags: usize,
+    pub(crate) addr: usize,
+    pub(crate) data: Vec<u8>,
+    /// The size of a `SHT_NOBITS` section, which has no data.
+    pub(crate) size: usize,
+    pub(crate) link: u32,
+    pub(crate) info: u32,
+    pub(crate) entsize: usize,
+}
+
+impl Section {
+    pub(crate) fn new(name: &'static str, kind: u32, data: Vec<u8>) -> Section {
+        Section { name, kind, flags: 0, addr: 0, size: data.len(), data, link: 0, info: 0, entsize: 0 }
+    }
+}
+
+/// Builds a relocatable object from `sections`, which are numbered from 1 in
+/// order. A section header string table is added after them.
+pub(crate) fn object(mut sections: Vec<Section>) -> Vec<u8> {
+    let mut names = StringTable::new();
+    let name_offsets: Vec<_> = sections.iter().map(|s| names.add(s.name)).collect();
+    let shstrndx = sections.len() + 1;
+    let shstrtab_name = names.add(".shstrtab");
+    sections.push(Section::new(".shstrtab", SHT_STRTAB, names.into_bytes()));
+
+    let header_size = if WORD == 8 { 64 } else { 52 };
+    let section_header_size = if WORD == 8 { 64 } else { 40 };
+
+    let mut out = Writer::default();
+    out.buf.resize(header_size, 0);
+
+    let mut offsets = Vec::with_capacity(sections.len());
+    for section in &sections {
+        out.align(WORD);
+        offsets.push(out.len());
+        out.buf.extend_from_slice(&section.data);
+    }
+
+    out.align(WORD);
+    let shoff = out.len();
+    out.buf.resize(shoff + section_header_size, 0);
+    let all_names = name_offsets.into_iter().chain(Some(shstrtab_name));
+    for ((section, offset), name) in sections.iter().zip(offsets).zip(all_names) {
+        out.u32(name);
+        out.u32(section.kind);
+        out.word(section.flags);
+        out.word(section.addr);
+        out.word(offset);
+        out.word(section.size);
+        out.u32(section.link);
+        out.u32(section.info);
+        out.word(WORD);
+        out.word(section.entsize);
+    }
+
+    let mut header = Writer::default();
+    header.buf.extend_from_slice(b"\x7fELF");
+    header.u8(if WORD == 8 { 2 } else { 1 });
+    header.u8(if cfg!(target_endian = "little") { 1 } else { 2 });
+    header.u8(1);
+    header.buf.resize(16, 0);
+    header.u16(ET_REL);
+    header.u16(MACHINE);
+    header.u32(1);
+    header.word(0);
+    header.word(0);
+    header.word(shoff);
+    header.u32(0);
+    header.u16(header_size as u16);
+    header.u16(0);
+    header.u16(0);
+    header.u16(section_header_size as u16);
+    header.u16(sections.len() as u16 + 1);
+    header.u16(shstrndx as u16);
+    out.buf[..header_size].copy_from_slice(&header.buf);
+
+    out.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let mut out = Writer::default();
+        out.uleb128(624_485);
+        out.sleb128(-123_456);
+        out.sleb128(63);
+        out.sleb128(64);
+        assert_eq!(out.buf, [0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0x3f, 0xc0, 0x00]);
+    }
+
+    #[test]
+    fn object_layout() {
+        let image = object(vec![Section::new(".data", SHT_PROGBITS, vec![1, 2, 3])]);
+        assert_eq!(&image[..4], b"\x7fELF");
+
+        let half = |at: usize| u16::from_ne_bytes([image[at], image[at + 1]]);
+        let (shnum, shstrndx) = if WORD == 8 { (60, 62) } else { (48, 50) };
+        assert_eq!(half(shnum), 3);
+        assert_eq!(half(shstrndx), 2);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::memory::Resource;
+use crate::phase::Emitted;
+use crate::JitState;
+
+use std::ffi::NulError;
+use std::fmt;
+
+/// The errors that operations on a `JitState` can report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A string passed to lightning contained a NUL byte.
+    InteriorNul(NulError),
+    /// The register `{class}{index}` does not exist on the current target.
+    InvalidRegister { class: char, index: i32 },
+    /// A null or empty buffer was given to lightning.
+    InvalidBuffer,
+    /// lightning failed to emit the code, because it could not map memory or
+    /// because the buffer given to `set_code` was too small.
+    EmitFailed,
+    /// A `CodeBuffer` could not provide the `size` bytes that the code needs.
+    CodeBufferFull { size: usize },
+    /// The protection of emitted code could not be changed; holds the `errno`
+    /// value that the system reported.
+    ProtectFailed(i32),
+    /// Emitting would take the memory of `resource` used by a `Jit` past
+    /// its quota of `limit` bytes; the code needs `requested` bytes, or for
+    /// `Resource::Ir` the states of the `Jit` already hold that many.
+    QuotaExceeded { resource: Resource, limit: usize, requested: usize },
+    /// `Jit::set_allocator` was called while lightning was running, or held
+    /// memory from the previous allocator.
+    AllocatorInUse,
+    /// No note covers the given address.
+    NoNote { address: usize },
+    /// `JitState::function` was given a signature with more parameters of
+    /// one class than there are registers to hold them.
+    TooManyParameters,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The result of emitting a `JitState`, which is in phase `P` on success.
+pub type EmitResult<'a, P = Emitted> = std::result::Result<JitState<'a, P>, EmitError<'a>>;
+
+/// The error that emitting a `JitState` reports, which gives the state back
+/// so that it can be emitted again, for instance into a larger buffer, or once
+/// dropping other code of its `Jit` has made room under the quota.
+///
+/// It converts into an `Error`, dropping the state, so `?` works in functions
+/// that return a `Result`.
+#[derive(Debug)]
+pub struct EmitError<'a> {
+    error: Error,
+    pub(crate) state: Option<JitState<'a>>,
+}
+
+impl<'a> EmitError<'a> {
+    pub(crate) fn new(error: Error, state: JitState<'a>) -> Self {
+        EmitError { error, state: Some(state) }
+    }
+
+    /// For failures once the code has been emitted, which leave no state to
+    /// give back.
+    pub(crate) fn after(error: Error) -> Self {
+        EmitError { error, state: None }
+    }
+
+    /// Returns what went wrong.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Returns the state as it was before emitting, or `None` if the code was
+    /// emitted but could not be made executable, which used the state up.
+    pub fn into_state(self) -> Option<JitState<'a>> {
+        self.state
+    }
+}
+
+impl fmt::Display for EmitError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for EmitError<'_> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        std::error::Error::source(&self.error)
+    }
+}
+
+impl From<EmitError<'_>> for Error {
+    fn from(e: EmitError<'_>) -> Error {
+        e.error
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InteriorNul(e) => write!(f, "string contains a NUL byte: {}", e),
+            Error::InvalidRegister { class, index } =>
+                write!(f, "register '{}{}' is not supported", class, index),
+            Error::InvalidBuffer => write!(f, "buffer is null or empty"),
+            Error::EmitFailed => write!(f, "failed to emit code"),
+            Error::CodeBufferFull { size } =>
+                write!(f, "code buffer cannot provide {} bytes", size),
+            Error::ProtectFailed(errno) => write!(
+                f,
+                "failed to change the protection of code: {}",
+                std::io::Error::from_raw_os_error(*errno),
+            ),
+            Error::QuotaExceeded { resource, limit, requested } => write!(
+                f,
+                "{} bytes of {:?} would exceed the quota of {} bytes",
+                requested, resource, limit,
+            ),
+            Error::AllocatorInUse => write!(f, "lightning is using the current allocator"),
+            Error::NoNote { address } => write!(f, "no note covers address {:#x}", address),
+            Error::TooManyParameters => write!(f, "not enough registers for the parameters"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InteriorNul(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Error {
+        Error::InteriorNul(e)
+    }
+}
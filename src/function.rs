@@ -0,0 +1,266 @@
+use crate::types::{FFISafe, ParamRegs};
+use crate::phase::Emitted;
+use crate::{Error, JitFn, JitFnPtr, JitState, Label, Result};
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// The body of a function being built by `JitState::function`.
+///
+/// It dereferences to the underlying `JitState`, so any instruction can be
+/// emitted through it, and provides return instructions that match the
+/// function's signature. These consume the builder, so nothing can be emitted
+/// after the return that ends the body.
+#[derive(Debug)]
+pub struct FunctionBuilder<'f, 'a, F> {
+    js: &'f mut JitState<'a>,
+    phantom: PhantomData<F>,
+}
+
+/// Proof that a function body ended in a return of type `R`.
+///
+/// Only the return methods of `FunctionBuilder` produce this value, and the
+/// lifetime `'f` ties it to the builder it came from, so a body cannot return
+/// one made by another.
+#[derive(Debug)]
+#[must_use]
+pub struct Return<'f, R>(Invariant<'f>, PhantomData<R>);
+
+/// Makes `'f` invariant, so that it cannot be shortened to match another
+/// builder.
+type Invariant<'f> = PhantomData<fn(&'f ()) -> &'f ()>;
+
+/// A function built by `JitState::function`, which resolves to a typed
+/// function pointer once the state has been emitted.
+#[derive(Clone, Debug)]
+pub struct Function<'a, F> {
+    entry: Label<'a>,
+    phantom: PhantomData<F>,
+}
+
+impl<'f, 'a, F: JitFnPtr> FunctionBuilder<'f, 'a, F> {
+    pub(crate) fn new(js: &'f mut JitState<'a>) -> Self {
+        FunctionBuilder { js, phantom: PhantomData }
+    }
+}
+
+impl<'f, 'a, F: JitFnPtr> FunctionBuilder<'f, 'a, F>
+    where F::Output: FFISafe
+{
+    /// Returns the value in `reg`, using the `retr` variant for the return
+    /// type.
+    pub fn retr(self, reg: <F::Output as FFISafe>::Reg) -> Return<'f, F::Output> {
+        F::Output::retr(self.js, reg);
+        Return(PhantomData, PhantomData)
+    }
+
+    /// Returns `imm`, using the `reti` variant for the return type.
+    pub fn reti(self, imm: F::Output) -> Return<'f, F::Output> {
+        F::Output::reti(self.js, imm);
+        Return(PhantomData, PhantomData)
+    }
+}
+
+impl<'f, 'a, F: JitFnPtr<Output = ()>> FunctionBuilder<'f, 'a, F> {
+    /// Returns from a function without a return value.
+    pub fn ret(self) -> Return<'f, ()> {
+        self.js.ret();
+        Return(PhantomData, PhantomData)
+    }
+}
+
+impl<'f, 'a, F> Deref for FunctionBuilder<'f, 'a, F> {
+    type Target = JitState<'a>;
+
+    fn deref(&self) -> &JitState<'a> {
+        self.js
+    }
+}
+
+impl<'f, 'a, F> DerefMut for FunctionBuilder<'f, 'a, F> {
+    fn deref_mut(&mut self) -> &mut JitState<'a> {
+        self.js
+    }
+}
+
+impl<'a, F: JitFnPtr> Function<'a, F> {
+    pub(crate) fn new(entry: Label<'a>) -> Self {
+        Function { entry, phantom: PhantomData }
+    }
+
+    /// Returns the label at the start of this function.
+    pub fn entry(&self) -> &Label<'a> {
+        &self.entry
+    }
+
+    /// Looks up this function in the code emitted from `js`.
+    pub fn resolve<'c, G>(&self, js: &'c JitState<'a, Emitted<G>>) -> JitFn<'c, F> {
+        // The signature was established when the function was built.
+        unsafe { js.code().at(js.address(&self.entry)) }
+    }
+}
+
+impl<'a> JitState<'a> {
+    /// Builds a complete function with the signature `F`.
+    ///
+    /// This emits the `prolog`, declares each parameter with the `arg` variant
+    /// matching its type and loads it with the matching `getarg` variant, then
+    /// hands the registers holding the parameters to `body`. Integer parameters
+    /// are loaded into `V` registers first and then `R` registers, and
+    /// floating-point parameters into `F` registers. `body` must end by calling
+    /// one of the return methods of the `FunctionBuilder`, after which the
+    /// `epilog` is emitted. Earlier returns, for instance in a branch, can be
+    /// emitted with the return instructions of `JitState`.
+    ///
+    /// Every parameter needs a register of its own, so `F` may have at most
+    /// `Jit::v_num() + Jit::r_num()` integer and `Jit::f_num()` floating-point
+    /// parameters; lightning guarantees six of each. Fails with
+    /// `Error::TooManyParameters` before emitting anything otherwise; such a
+    /// function can still be built with `prolog`, `arg` and `getarg`.
+    ///
+    /// The type of `body` has to be given as `_` when naming `F`, since `impl
+    /// Trait` arguments cannot be combined with explicit generic arguments on
+    /// our minimum supported Rust version.
+    ///
+    /// ```
+    /// use lightning_sys::{FReg, Jit};
+    ///
+    /// let mut jit = Jit::new();
+    /// let mut js = jit.new_state();
+    ///
+    /// let func = js.function::<extern "C" fn(i32, f64) -> f64, _>(|mut f, (a, x)| {
+    ///     f.extr_d(FReg::F(1), a);
+    ///     f.addr_d(FReg::F(1), FReg::F(1), x);
+    ///     f.retr(FReg::F(1))
+    /// }).unwrap();
+    ///
+    /// let js = js.emit().unwrap();
+    /// let func = func.resolve(&js);
+    ///
+    /// assert_eq!(func.call(1, 2.5), 3.5);
+    /// ```
+    ///
+    /// A body that does not end in a return of the declared type is rejected:
+    ///
+    /// ```compile_fail,E0308
+    /// use lightning_sys::{Jit, JitWord, Reg};
+    ///
+    /// let mut jit = Jit::new();
+    /// let mut js = jit.new_state();
+    ///
+    /// js.function::<extern "C" fn(JitWord) -> JitWord, _>(|mut f, (a,)| {
+    ///     f.addi(Reg::R(0), a, 1);
+    /// }).unwrap();
+    /// ```
+    pub fn function<F, B>(&mut self, body: B) -> Result<Function<'a, F>>
+        where F: JitFnPtr,
+              B: for<'f> FnOnce(FunctionBuilder<'f, 'a, F>, F::Params) -> Return<'f, F::Output>,
+    {
+        let params = F::param_regs(&mut ParamRegs::default()).ok_or(Error::TooManyParameters)?;
+        let entry = self.label();
+        self.prolog();
+        F::load_params(self, params);
+        let _ = body(FunctionBuilder::new(self), params);
+        self.epilog();
+        Ok(Function::new(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Jit, JitWord, Reg};
+
+    #[test]
+    fn mixed_params() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+
+        let scale = js.function::<extern "C" fn(f32, u32, JitWord) -> JitWord, _>(|mut f, (x, n, w)| {
+            f.truncr_f(Reg::R(0), x);
+            f.mulr(Reg::R(0), Reg::R(0), n);
+            f.addr(Reg::R(0), Reg::R(0), w);
+            f.retr(Reg::R(0))
+        }).unwrap();
+        let seven = js.function::<extern "C" fn() -> i32, _>(|f, ()| f.reti(7)).unwrap();
+
+        let js = js.emit().unwrap();
+        let scale = scale.resolve(&js);
+        let seven = seven.resolve(&js);
+
+        assert_eq!(scale.call(3.5, 4, 2), 14);
+        assert_eq!(seven.call(), 7);
+    }
+
+    #[test]
+    fn no_return_value() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        let mut out: i32 = 0;
+
+        let store = js.function::<extern "C" fn(i32), _>(|mut f, (v,)| {
+            f.sti_i(&mut out as *mut i32 as _, v);
+            f.ret()
+        }).unwrap();
+
+        let js = js.emit().unwrap();
+        let store = store.resolve(&js);
+
+        store.call(42);
+        assert_eq!(out, 42);
+    }
+
+    #[test]
+    fn narrow_params() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        let value: i32 = 100;
+
+        let sum = js.function::<extern "C" fn(i8, u16, *const i32) -> JitWord, _>(|mut f, (a, b, p)| {
+            f.ldr_i(Reg::R(0), p.into());
+            f.addr(Reg::R(0), Reg::R(0), a);
+            f.addr(Reg::R(0), Reg::R(0), b);
+            f.retr(Reg::R(0))
+        }).unwrap();
+
+        let js = js.emit().unwrap();
+        let sum = sum.resolve(&js);
+
+        assert_eq!(sum.call(-1, 65535, &value), 100 - 1 + 65535);
+    }
+
+    #[test]
+    fn float_compare() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+
+        let less = js.function::<extern "C" fn(f64, f64) -> i32, _>(|mut f, (a, b)| {
+            f.ltr_d(Reg::R(0), a, b);
+            f.retr(Reg::R(0))
+        }).unwrap();
+
+        let js = js.emit().unwrap();
+        let less = less.resolve(&js);
+
+        assert_eq!(less.call(1.0, 2.0), 1);
+        assert_eq!(less.call(2.0, 1.0), 0);
+    }
+
+    #[test]
+    fn too_many_params() {
+        type Wide = extern "C" fn(JitWord, JitWord, JitWord, JitWord, JitWord, JitWord,
+                                  JitWord, JitWord, JitWord, JitWord, JitWord, JitWord) -> JitWord;
+
+        if Jit::v_num() + Jit::r_num() >= 12 {
+            return;
+        }
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        let result = js.function::<Wide, _>(|f, params| f.retr(params.0));
+        assert_eq!(result.map(|_| ()), Err(Error::TooManyParameters));
+
+        // Nothing was emitted, so the state can go on.
+        let one = js.function::<extern "C" fn() -> JitWord, _>(|f, ()| f.reti(1)).unwrap();
+        let js = js.emit().unwrap();
+        assert_eq!(one.resolve(&js).call(), 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! Registration of emitted code with GDB.
+//!
+//! With the `gdb` feature, when a state that used `JitState::name` or
+//! `JitState::note` is emitted, its `CompiledCode` hands GDB an in-memory ELF
+//! object through the JIT compilation interface. The object has a symbol for
+//! each named function and a DWARF line table built from the notes, so that
+//! breakpoints and backtraces work in the generated code. It is withdrawn
+//! when the `CompiledCode` is dropped.
+//!
+//! Building the object, and registering it under the lock that the interface
+//! needs, costs every emission, so it is left to programs that are debugged.
+
+use crate::bindings;
+use crate::elf::{self, Section, StringTable, Writer};
+use crate::notes::{Function, Notes};
+use crate::JitWord;
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+// Sections of the object, in the order passed to `elf::object`.
+const TEXT: u16 = 1;
+const STRTAB: u32 = 2;
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_STRING: u64 = 0x08;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// An object registered with GDB, which is withdrawn when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    entry: usize,
+    // GDB reads the object from this memory for as long as it is registered.
+    #[allow(dead_code)]
+    image: Vec<u8>,
+}
+
+/// A row of the line table.
+#[derive(Debug)]
+struct Line<'s> {
+    address: usize,
+    file: Cow<'s, str>,
+    line: i32,
+}
+
+impl Registration {
+    /// Registers the functions and lines recorded in `notes` that lie within
+    /// `code`, if there are any.
+    pub(crate) fn new(notes: Notes<'_>, code: Range<usize>) -> Option<Registration> {
+        let mut functions = notes.functions();
+        functions.retain(|f| code.contains(&f.start));
+        let lines: Vec<_> = notes
+            .filter(|&(at, _)| code.contains(&(at as usize)))
+            .filter_map(|(at, info)| {
+                let file = info.file?;
+                Some(Line { address: at as usize, file, line: info.line })
+            })
+            .collect();
+        if functions.iter().all(|f| f.name.is_none()) && lines.is_empty() {
+            return None;
+        }
+
+        let image = image(&functions, &lines);
+        let entry = unsafe {
+            bindings::lgsys_gdb_register(image.as_ptr() as *const _, image.len() as JitWord)
+        };
+        if entry.is_null() {
+            return None;
+        }
+        Some(Registration { entry: entry as usize, image })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        unsafe { bindings::lgsys_gdb_unregister(self.entry as bindings::jit_pointer_t) };
+    }
+}
+
+/// Builds an object describing `functions`, with `lines` as its line table.
+fn image(functions: &[Function<'_>], lines: &[Line<'_>]) -> Vec<u8> {
+    let start = functions.iter().map(|f| f.start).min().unwrap_or(0);
+    let end = functions.iter().map(|f| f.start + f.size).max().unwrap_or(start);
+    let named = || functions.iter().filter_map(|f| f.name.as_ref().map(|name| (name, f)));
+
+    let mut strings = StringTable::new();
+    let mut symbols = Writer::default();
+    elf::symbol(&mut symbols, 0, 0, 0, 0, 0);
+    let file = strings.add("lightning");
+    elf::symbol(&mut symbols, file, elf::STB_LOCAL << 4 | elf::STT_FILE, elf::SHN_ABS, 0, 0);
+    for (name, function) in named() {
+        let name = strings.add(name);
+        let info = elf::STB_GLOBAL << 4 | elf::STT_FUNC;
+        elf::symbol(&mut symbols, name, info, TEXT, function.start - start, function.size);
+    }
+
+    let mut abbrev = Writer::default();
+    for &(code, tag, children, attributes) in &[
+        (1, DW_TAG_COMPILE_UNIT, 1, &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_STMT_LIST, DW_FORM_DATA4),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_ADDR),
+        ][..]),
+        (2, DW_TAG_SUBPROGRAM, 0, &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_ADDR),
+        ][..]),
+    ] {
+        abbrev.uleb128(code);
+        abbrev.uleb128(tag);
+        abbrev.u8(children);
+        for &(name, form) in attributes {
+            abbrev.uleb128(name);
+            abbrev.uleb128(form);
+        }
+        abbrev.u16(0);
+    }
+    abbrev.u8(0);
+
+    let mut info = Writer::default();
+    info.u32(0);
+    info.u16(2);
+    info.u32(0);
+    info.u8(std::mem::size_of::<usize>() as u8);
+    info.uleb128(1);
+    info.c_str("lightning");
+    info.u32(0);
+    info.word(start);
+    info.word(end);
+    for (name, function) in named() {
+        info.uleb128(2);
+        info.c_str(name);
+        info.word(function.start);
+        info.word(function.start + function.size);
+    }
+    info.u8(0);
+    let length = info.len() as u32 - 4;
+    info.patch_u32(0, length);
+
+    let mut text = Section::new(".text", elf::SHT_NOBITS, Vec::new());
+    text.flags = elf::SHF_ALLOC | elf::SHF_EXECINSTR;
+    text.addr = start;
+    text.size = end - start;
+
+    let mut symtab = Section::new(".symtab", elf::SHT_SYMTAB, symbols.buf);
+    symtab.link = STRTAB;
+    symtab.info = 2; // the first global symbol
+    symtab.entsize = elf::SYMBOL_SIZE;
+
+    elf::object(vec![
+        text,
+        Section::new(".strtab", elf::SHT_STRTAB, strings.into_bytes()),
+        symtab,
+        Section::new(".debug_abbrev", elf::SHT_PROGBITS, abbrev.buf),
+        Section::new(".debug_info", elf::SHT_PROGBITS, info.buf),
+        Section::new(".debug_line", elf::SHT_PROGBITS, line_table(functions, lines)),
+    ])
+}
+
+/// Builds a DWARF 2 line table, with a sequence for each function.
+fn line_table(functions: &[Function<'_>], lines: &[Line<'_>]) -> Vec<u8> {
+    let mut files: Vec<&str> = Vec::new();
+    for line in lines {
+        if !files.contains(&&*line.file) {
+            files.push(&line.file);
+        }
+    }
+
+    let mut out = Writer::default();
+    out.u32(0);
+    out.u16(2);
+    out.u32(0);
+    let header_start = out.len();
+    out.u8(1); // minimum_instruction_length
+    out.u8(1); // default_is_stmt
+    out.u8(-5i8 as u8); // line_base
+    out.u8(14); // line_range
+    out.u8(13); // opcode_base
+    out.buf.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    out.u8(0); // no include_directories
+    for file in &files {
+        out.c_str(file);
+        out.uleb128(0);
+        out.uleb128(0);
+        out.uleb128(0);
+    }
+    out.u8(0);
+    let header_length = (out.len() - header_start) as u32;
+    out.patch_u32(header_start - 4, header_length);
+
+    let set_address = |out: &mut Writer, address: usize| {
+        out.u8(0);
+        out.uleb128(1 + std::mem::size_of::<usize>() as u64);
+        out.u8(DW_LNE_SET_ADDRESS);
+        out.word(address);
+    };
+
+    for function in functions {
+        let end = function.start + function.size;
+        let rows = lines.iter().filter(|l| l.address >= function.start && l.address < end);
+
+        let (mut file, mut line, mut any) = (1, 1, false);
+        for row in rows {
+            set_address(&mut out, row.address);
+            let index = files.iter().position(|f| *f == row.file).unwrap() + 1;
+            if index != file {
+                out.u8(DW_LNS_SET_FILE);
+                out.uleb128(index as u64);
+                file = index;
+            }
+            if row.line != line {
+                out.u8(DW_LNS_ADVANCE_LINE);
+                out.sleb128(i64::from(row.line) - i64::from(line));
+                line = row.line;
+            }
+            out.u8(DW_LNS_COPY);
+            any = true;
+        }
+
+        if any {
+            set_address(&mut out, end);
+            out.u8(0);
+            out.uleb128(1);
+            out.u8(DW_LNE_END_SEQUENCE);
+        }
+    }
+
+    let length = out.len() as u32 - 4;
+    out.patch_u32(0, length);
+    out.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Jit, Reg};
+
+    use std::convert::TryInto;
+
+    const WORD: usize = std::mem::size_of::<usize>();
+
+    /// An object built by `image`, read back as GDB would.
+    struct Object<'i> {
+        image: &'i [u8],
+        sections: Vec<SectionHeader>,
+    }
+
+    #[derive(Debug)]
+    struct SectionHeader {
+        name: String,
+        kind: u32,
+        addr: usize,
+        offset: usize,
+        size: usize,
+        link: u32,
+    }
+
+    /// A row of the line table: its address, file and line, and whether it
+    /// ends a sequence.
+    type Row = (usize, String, i64, bool);
+
+    impl<'i> Object<'i> {
+        fn parse(image: &'i [u8]) -> Object<'i> {
+            let mut object = Object { image, sections: Vec::new() };
+            assert_eq!(&image[..4], b"\x7fELF");
+            assert_eq!(image[4], if WORD == 8 { 2 } else { 1 });
+            assert_eq!(image[5], if cfg!(target_endian = "little") { 1 } else { 2 });
+            assert_eq!(object.u16(16), 1); // ET_REL
+            assert_eq!(object.u16(18), elf::MACHINE);
+
+            let (shoff, shentsize, shnum, shstrndx) = if WORD == 8 {
+                (object.word(40), object.u16(58), object.u16(60), object.u16(62))
+            } else {
+                (object.word(32), object.u16(46), object.u16(48), object.u16(50))
+            };
+            let headers: Vec<_> = (0..usize::from(shnum))
+                .map(|i| {
+                    let at = shoff + i * usize::from(shentsize);
+                    let name = object.u32(at);
+                    let header = SectionHeader {
+                        name: String::new(),
+                        kind: object.u32(at + 4),
+                        addr: object.word(at + 8 + WORD),
+                        offset: object.word(at + 8 + 2 * WORD),
+                        size: object.word(at + 8 + 3 * WORD),
+                        link: object.u32(at + 8 + 4 * WORD),
+                    };
+                    (name, header)
+                })
+                .collect();
+            let names = headers[usize::from(shstrndx)].1.offset;
+            object.sections = headers
+                .into_iter()
+                .map(|(name, header)| SectionHeader { name: object.c_str(names + name as usize).into(), ..header })
+                .collect();
+            object
+        }
+
+        fn section(&self, name: &str) -> &SectionHeader {
+            self.sections.iter().find(|s| s.name == name).unwrap()
+        }
+
+        /// Returns the name, binding and type, section, address and size of
+        /// each symbol.
+        fn symbols(&self) -> Vec<(String, u8, u16, usize, usize)> {
+            let symtab = self.section(".symtab");
+            let strtab = self.sections[symtab.link as usize].offset;
+            (0..symtab.size / elf::SYMBOL_SIZE)
+                .map(|i| {
+                    let at = symtab.offset + i * elf::SYMBOL_SIZE;
+                    let name = self.c_str(strtab + self.u32(at) as usize).to_string();
+                    let (info, shndx, value, size) = if WORD == 8 {
+                        (self.image[at + 4], self.u16(at + 6), self.word(at + 8), self.word(at + 16))
+                    } else {
+                        (self.image[at + 12], self.u16(at + 14), self.word(at + 4), self.word(at + 8))
+                    };
+                    let addr = if shndx == TEXT { self.section(".text").addr + value } else { value };
+                    (name, info, shndx, addr, size)
+                })
+                .collect()
+        }
+
+        /// Runs the line number program.
+        fn lines(&self) -> Vec<Row> {
+            let section = self.section(".debug_line");
+            let end = section.offset + 4 + self.u32(section.offset) as usize;
+            assert_eq!(end, section.offset + section.size);
+            assert_eq!(self.u16(section.offset + 4), 2);
+            let program = section.offset + 10 + self.u32(section.offset + 6) as usize;
+
+            let mut at = section.offset + 10;
+            let opcode_base = self.image[at + 4];
+            at += 4 + usize::from(opcode_base);
+            assert_eq!(self.image[at], 0, "include directories");
+            at += 1;
+            let mut files = Vec::new();
+            while self.image[at] != 0 {
+                let name = self.c_str(at);
+                at += name.len() + 1;
+                for _ in 0..3 {
+                    self.uleb128(&mut at);
+                }
+                files.push(name.to_string());
+            }
+            assert_eq!(at + 1, program);
+
+            let mut rows = Vec::new();
+            let (mut address, mut file, mut line) = (0, 1, 1);
+            at = program;
+            while at < end {
+                let opcode = self.image[at];
+                at += 1;
+                match opcode {
+                    0 => {
+                        let length = self.uleb128(&mut at) as usize;
+                        match self.image[at] {
+                            DW_LNE_END_SEQUENCE => {
+                                rows.push((address, files[file - 1].clone(), line, true));
+                                address = 0;
+                                file = 1;
+                                line = 1;
+                            }
+                            DW_LNE_SET_ADDRESS => address = self.word(at + 1),
+                            other => panic!("extended opcode {}", other),
+                        }
+                        at += length;
+                    }
+                    DW_LNS_COPY => rows.push((address, files[file - 1].clone(), line, false)),
+                    DW_LNS_ADVANCE_LINE => line += self.sleb128(&mut at),
+                    DW_LNS_SET_FILE => file = self.uleb128(&mut at) as usize,
+                    other => panic!("opcode {}", other),
+                }
+            }
+            rows
+        }
+
+        fn u16(&self, at: usize) -> u16 {
+            u16::from_ne_bytes(self.image[at..at + 2].try_into().unwrap())
+        }
+
+        fn u32(&self, at: usize) -> u32 {
+            u32::from_ne_bytes(self.image[at..at + 4].try_into().unwrap())
+        }
+
+        fn word(&self, at: usize) -> usize {
+            usize::from_ne_bytes(self.image[at..at + WORD].try_into().unwrap())
+        }
+
+        fn c_str(&self, at: usize) -> &str {
+            let len = self.image[at..].iter().position(|&b| b == 0).unwrap();
+            std::str::from_utf8(&self.image[at..at + len]).unwrap()
+        }
+
+        fn uleb128(&self, at: &mut usize) -> u64 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = self.image[*at];
+                *at += 1;
+                value |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+
+        fn sleb128(&self, at: &mut usize) -> i64 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = self.image[*at];
+                *at += 1;
+                value |= i64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    if shift < 64 && byte & 0x40 != 0 {
+                        value |= -1 << shift;
+                    }
+                    return value;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn image_layout() {
+        let function = |name: Option<&'static str>, start, size| Function { name: name.map(Cow::from), start, size };
+        let line = |address, file: &'static str, line| Line { address, file: file.into(), line };
+        let functions = [
+            function(Some("gdb_first"), 0x1000, 0x20),
+            function(None, 0x1020, 0x10),
+            function(Some("gdb_second"), 0x1030, 0x30),
+        ];
+        let lines = [line(0x1000, "a.rs", 10), line(0x1008, "a.rs", 12), line(0x1030, "b.rs", 3), line(0x1040, "a.rs", 1)];
+        let image = image(&functions, &lines);
+        let object = Object::parse(&image);
+
+        let names: Vec<_> = object.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["", ".text", ".strtab", ".symtab", ".debug_abbrev", ".debug_info", ".debug_line", ".shstrtab"]
+        );
+        let text = object.section(".text");
+        assert_eq!((text.kind, text.addr, text.size), (elf::SHT_NOBITS, 0x1000, 0x60));
+
+        let global = elf::STB_GLOBAL << 4 | elf::STT_FUNC;
+        assert_eq!(
+            object.symbols(),
+            [
+                (String::new(), 0, 0, 0, 0),
+                ("lightning".to_string(), elf::STB_LOCAL << 4 | elf::STT_FILE, elf::SHN_ABS, 0, 0),
+                ("gdb_first".to_string(), global, TEXT, 0x1000, 0x20),
+                ("gdb_second".to_string(), global, TEXT, 0x1030, 0x30),
+            ]
+        );
+
+        // The anonymous function has no lines, so no sequence.
+        let row = |address, file: &str, line, end| (address, file.to_string(), line, end);
+        assert_eq!(
+            object.lines(),
+            [
+                row(0x1000, "a.rs", 10, false),
+                row(0x1008, "a.rs", 12, false),
+                row(0x1020, "a.rs", 12, true),
+                row(0x1030, "b.rs", 3, false),
+                row(0x1040, "a.rs", 1, false),
+                row(0x1060, "a.rs", 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_named_code() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.name(Some("gdb_answer")).unwrap();
+        js.note(Some("gdb.rs"), 42).unwrap();
+        js.prolog();
+        js.movi(Reg::R(0), 42);
+        js.retr(Reg::R(0));
+        js.epilog();
+
+        let code = js.emit().unwrap().into_code();
+        let object = Object::parse(&code.debug.as_ref().unwrap().image);
+        let entry = code.entry_address() as usize;
+        let symbol = object.symbols().into_iter().find(|s| s.0 == "gdb_answer").unwrap();
+        assert_eq!(symbol.3, entry);
+        assert!(symbol.4 > 0);
+        let lines = object.lines();
+        assert_eq!(lines[0], (entry, "gdb.rs".to_string(), 42, false));
+        assert!(lines.last().unwrap().3);
+    }
+
+    #[test]
+    fn skips_anonymous_code() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(0);
+        js.epilog();
+
+        let code = js.emit().unwrap().into_code();
+        assert!(code.debug.is_none());
+    }
+}
@@ -2,48 +2,125 @@
 #![allow(clippy::new_without_default)] // Avoid clippy warning about Jit::new
 #![deny(unused_must_use)]
 
-use std::sync::Mutex;
+use std::alloc::GlobalAlloc;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use crate::bindings;
 use crate::JitState;
+use crate::memory::{self, Accounting, MemoryQuota, MemoryUsage};
+use crate::Result;
+use crate::types::StateId;
+use crate::phase::Building;
 
 use std::marker::PhantomData;
 
 #[derive(Debug)]
-pub struct Jit<'a>(PhantomData<&'a ()>);
+pub struct Jit<'a> {
+    // Shared with the states made by this `Jit` and the code they emit.
+    accounting: Arc<Accounting>,
+    phantom: PhantomData<&'a ()>,
+}
 
 lazy_static! {
     static ref JITS_MADE: Mutex<usize> = Mutex::new(0);
+    // lightning may keep the pointer to the program path given to `init_jit`.
+    static ref PROGRAM: Mutex<Option<CString>> = Mutex::new(None);
 }
 
 impl<'a> Jit<'a> {
     #[must_use]
     pub fn new() -> Jit<'a> {
+        Jit::init(None)
+    }
+
+    /// Like `new`, but tells lightning that the running program is at `path`;
+    /// the disassembler reads the names of symbols from it. `new` uses
+    /// `std::env::current_exe` instead.
+    ///
+    /// lightning is initialized once for all the `Jit`s that exist at the same
+    /// time, so `path` is ignored if another `Jit` is alive.
+    pub fn with_program(path: impl AsRef<Path>) -> Result<Jit<'a>> {
+        Ok(Jit::init(Some(path_to_c_string(path.as_ref())?)))
+    }
+
+    fn init(program: Option<CString>) -> Jit<'a> {
         let mut m = JITS_MADE.lock().unwrap();
 
         if *m == 0 {
+            memory::install_hooks();
+            let mut stored = PROGRAM.lock().unwrap();
+            *stored = program.or_else(|| {
+                let exe = std::env::current_exe().ok()?;
+                path_to_c_string(&exe).ok()
+            });
             unsafe {
-                //TODO: figure out how to get ptr to argv[0]
-                bindings::init_jit(std::ptr::null());
+                bindings::init_jit(stored.as_ref().map_or(std::ptr::null(), |p| p.as_ptr()));
             }
         }
 
         *m += 1;
-        Jit(PhantomData)
+        Jit { accounting: Arc::default(), phantom: PhantomData }
     }
 
     // This takes &mut self instead of &self because the unsafe operations wrapped herein are
     // inherently mutating.
     #[must_use]
     pub fn new_state(&mut self) -> JitState {
+        self.accounting.add_state();
+        let state = {
+            let _attributed = memory::attribute(&self.accounting, std::ptr::null_mut());
+            unsafe { bindings::jit_new_state() }
+        };
         JitState {
-            state: unsafe {
-                bindings::jit_new_state()
-            },
+            state,
+            id: StateId::next(),
+            phase: Building,
+            accounting: Arc::clone(&self.accounting),
+            allocai: Vec::new(),
+            storage: None,
             phantom: PhantomData,
         }
     }
 
+    /// Returns the memory used by the live states of this `Jit` and the live
+    /// code emitted from them.
+    pub fn usage(&self) -> MemoryUsage {
+        self.accounting.usage()
+    }
+
+    /// Returns the limits set by `set_quota`.
+    pub fn quota(&self) -> MemoryQuota {
+        self.accounting.quota()
+    }
+
+    /// Limits the memory that the live code of this `Jit` may use. Emitting a
+    /// state fails with `Error::QuotaExceeded` if the memory lightning would
+    /// map for its code, or the size of its constants or notes, would take
+    /// the usage past the quota. Code that is already live is kept.
+    ///
+    /// Since building a state cannot fail, a state whose nodes take the memory
+    /// of the states past `MemoryQuota::ir` fails when it is emitted.
+    pub fn set_quota(&mut self, quota: MemoryQuota) {
+        self.accounting.set_quota(quota);
+    }
+
+    /// Makes lightning take the memory for its states from `allocator`
+    /// instead of the global allocator, for instance to account for it or to
+    /// check it for leaks. The allocator of emitted code is not affected.
+    ///
+    /// The allocator can only be replaced while no `Jit` exists and lightning
+    /// holds no memory from the previous one; fails with
+    /// `Error::AllocatorInUse` otherwise.
+    pub fn set_allocator(allocator: &'static (dyn GlobalAlloc + Sync)) -> Result<()> {
+        let m = JITS_MADE.lock().unwrap();
+        if *m != 0 {
+            return Err(crate::Error::AllocatorInUse);
+        }
+        memory::set_allocator(allocator)
+    }
+
     #[must_use]
     pub fn r_num() -> bindings::jit_gpr_t {
         unsafe {
@@ -67,6 +144,17 @@ impl<'a> Jit<'a> {
 
 }
 
+#[cfg(unix)]
+fn path_to_c_string(path: &Path) -> Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(not(unix))]
+fn path_to_c_string(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.to_string_lossy().into_owned())?)
+}
+
 impl<'a> Drop for Jit<'a> {
     fn drop(&mut self) {
         let mut m = JITS_MADE.lock().unwrap();
@@ -83,7 +171,7 @@ impl<'a> Drop for Jit<'a> {
 #[cfg(test)]
 mod tests {
     use crate::Jit;
-    use crate::Reg;
+    use crate::{FReg, Reg};
     use crate::types::ToFFI;
 
     #[test]
@@ -100,6 +188,12 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_with_program() {
+        let _jit = Jit::with_program(std::env::current_exe().unwrap()).unwrap();
+        assert!(Jit::with_program("bad\0path").is_err());
+    }
+
     #[test]
     fn test_reg_num() {
         assert!(Jit::r_num() >= 3);
@@ -108,21 +202,36 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_r_invalid() { let _ = Reg::R(Jit::r_num()).to_ffi(); }
+    #[should_panic(expected = "is not supported")]
+    fn test_r_invalid() { let _ = Reg::R(Jit::r_num()); }
 
     #[test]
-    #[should_panic]
-    fn test_v_invalid() { let _ = Reg::R(Jit::v_num()).to_ffi(); }
+    #[should_panic(expected = "is not supported")]
+    fn test_v_invalid() { let _ = Reg::V(Jit::v_num()); }
 
     #[test]
-    #[should_panic]
-    fn test_f_invalid() { let _ = Reg::R(Jit::f_num()).to_ffi(); }
+    #[should_panic(expected = "is not supported")]
+    fn test_f_invalid() { let _ = FReg::F(Jit::f_num()); }
+
+    #[test]
+    fn test_try_reg() {
+        assert!(Reg::try_r(Jit::r_num()).is_err());
+        assert!(Reg::try_v(-1).is_err());
+        assert!(FReg::try_f(Jit::f_num()).is_err());
+        assert_eq!(Reg::try_r(0), Ok(Reg::R(0)));
+        assert_eq!(
+            Reg::try_v(Jit::v_num()).unwrap_err().to_string(),
+            format!("register 'V{}' is not supported", Jit::v_num()),
+        );
+    }
 
     #[test]
     fn test_to_ffi() {
-        for n in 0..Jit::r_num() { let _ = Reg::R(n).to_ffi(); }
-        for n in 0..Jit::v_num() { let _ = Reg::V(n).to_ffi(); }
-        for n in 0..Jit::f_num() { let _ = Reg::F(n).to_ffi(); }
+        assert_eq!(Reg::all_r().count(), Jit::r_num() as usize);
+        assert_eq!(Reg::all_v().count(), Jit::v_num() as usize);
+        assert_eq!(FReg::all().count(), Jit::f_num() as usize);
+        for reg in Reg::all_r() { let _ = reg.to_ffi(); }
+        for reg in Reg::all_v() { let _ = reg.to_ffi(); }
+        for reg in FReg::all() { let _ = reg.to_ffi(); }
     }
 }
@@ -1,42 +1,83 @@
 use crate::bindings;
-use crate::Reg;
+use crate::{CodeAddr, CodeBuffer, CompiledCode};
+use crate::JitFnPtr;
+use crate::types::FFISafe;
+use crate::{Base, FReg, Reg};
 use crate::JitNode;
+use crate::memory::{self, Accounting, Charge};
+use crate::notes::{NoteInfo, Notes};
+use crate::node::{Addressable, Arg, Branch, CallSite, Forward, Label, Node, Note, Patchable, Target};
 use crate::{JitWord, JitPointer};
 use crate::ToFFI;
-use std::ffi::CString;
+use crate::{EmitError, EmitResult, Error, Result};
+use crate::types::StateId;
+use crate::phase::{Building, Cleared, Emitted, Uncleared};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Mutex};
 use tt_call::*;
 
+/// A lightning state, in the phase `P`; see the `phase` module.
 #[derive(Debug)]
-pub struct JitState<'a> {
+pub struct JitState<'a, P = Building> {
     pub(crate) state: *mut bindings::jit_state_t,
+    pub(crate) id: StateId,
+    pub(crate) phase: P,
+    pub(crate) accounting: Arc<Accounting>,
+    // The bytes requested with `allocai` by each function begun with
+    // `prolog`, for `stats`.
+    pub(crate) allocai: Vec<usize>,
+    // Storage given to `DataBuilder::storage`, which the emitted code keeps.
+    pub(crate) storage: Option<Box<[u8]>>,
     pub(crate) phantom: std::marker::PhantomData<&'a ()>,
 }
 
-impl<'a> Drop for JitState<'a> {
+lazy_static! {
+    // `lgsys_print` points the C `stdout` at its own stream while it runs.
+    static ref PRINT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+impl<'a, P> Drop for JitState<'a, P> {
     fn drop(&mut self) {
         unsafe {
             bindings::_jit_destroy_state(self.state);
         }
+        self.accounting.remove_state();
     }
 }
 
 macro_rules! jit_reexport {
+    ( @node $self:ident, $orig:ident $(, $arg:ident )* ) => {{
+        $( $arg.check_state($self.id); )*
+        JitNode{
+            node: unsafe { bindings::$orig(*$self.raw() $(, $arg.to_ffi())*) },
+            state: $self.id,
+            phantom: std::marker::PhantomData,
+        }
+    }};
     ( $orig:ident, $fn:ident $(, $arg:ident : $typ:ty )*; -> JitNode<$life:lifetime>) => {
         pub fn $fn(&mut self $(, $arg: $typ )*) -> JitNode<$life> {
-            JitNode{
-                node: unsafe { bindings::$orig(self.state $(, $arg.to_ffi())*) },
-                phantom: std::marker::PhantomData,
-            }
+            jit_reexport!(@node self, $orig $(, $arg )*)
+        }
+    };
+    ( $orig:ident, $fn:ident $(, $arg:ident : $typ:ty )*; -> $kind:ident<$life:lifetime>) => {
+        pub fn $fn(&mut self $(, $arg: $typ )*) -> $kind<$life> {
+            $kind(jit_reexport!(@node self, $orig $(, $arg )*))
         }
     };
     ( $orig:ident, $fn:ident $(, $arg:ident : $typ:ty )*; -> bool) => {
         pub fn $fn(&mut self $(, $arg: $typ )*) -> bool {
-            unsafe { bindings::$orig(self.state $(, $arg.to_ffi())*) != 0 }
+            $( $arg.check_state(self.id); )*
+            unsafe { bindings::$orig(*self.raw() $(, $arg.to_ffi())*) != 0 }
         }
     };
     ( $orig:ident, $fn:ident $(, $arg:ident : $typ:ty )*; -> $ret:ty) => {
         pub fn $fn(&mut self $(, $arg: $typ )*) -> $ret {
-            unsafe { bindings::$orig(self.state $(, $arg.to_ffi())*) }
+            $( $arg.check_state(self.id); )*
+            unsafe { bindings::$orig(*self.raw() $(, $arg.to_ffi())*) }
         }
     };
     ( $orig:ident, $fn:ident $(, $arg:ident : $typ:ty )*) => { jit_reexport!($orig, $fn $(, $arg : $typ)*; -> ()); }
@@ -51,48 +92,217 @@ macro_rules! jit_alias {
     ( $targ:ident => $new:ident $(, $arg:ident : $typ:ty )*) => { jit_alias!($targ => $new $(, $arg : $typ)*; -> ()); }
 }
 
+/// A `getarg` variant for integer arguments.
+pub(crate) type GetargFn = unsafe extern "C" fn(
+    *mut bindings::jit_state_t,
+    bindings::jit_gpr_t,
+    *mut bindings::jit_node_t,
+);
+
+/// Returns a pointer to the string in `cs`, or null.
+fn c_str_or_null(cs: &Option<CString>) -> *const std::os::raw::c_char {
+    cs.as_ref().map_or(std::ptr::null(), |c| c.as_ptr())
+}
+
 /// Convert a nullable reference into the C type representing it.
 fn pointer_from<T>(p: Option<&mut T>) -> * mut T {
     p.map(|x| x as _).unwrap_or(std::ptr::null_mut())
 }
 
+impl<'a, P> JitState<'a, P> {
+    /// Returns the lightning state, counting what lightning allocates on this
+    /// thread against the `Jit` of this state until the result is dropped,
+    /// which for a temporary is at the end of the statement.
+    pub(crate) fn raw(&self) -> memory::Attributed {
+        memory::attribute(&self.accounting, self.state)
+    }
+
+    /// Returns the id of this state, which the code it emits carries; see
+    /// `CompiledCode::state_id`.
+    pub fn id(&self) -> StateId {
+        self.id
+    }
+
+    /// Moves this state into the phase that `f` computes from its current
+    /// phase.
+    fn into_phase<Q>(self, f: impl FnOnce(P) -> Q) -> JitState<'a, Q> {
+        let this = std::mem::ManuallyDrop::new(self);
+        // `this` is never dropped, so its phase is only moved out once here
+        let phase = unsafe { std::ptr::read(&this.phase) };
+        let accounting = unsafe { std::ptr::read(&this.accounting) };
+        let allocai = unsafe { std::ptr::read(&this.allocai) };
+        let storage = unsafe { std::ptr::read(&this.storage) };
+        JitState {
+            state: this.state,
+            id: this.id,
+            phase: f(phase),
+            accounting,
+            allocai,
+            storage,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns the untyped node for `node`, after checking that it belongs to
+    /// this state.
+    fn raw_node(&self, node: &impl Node<'a>) -> *mut bindings::jit_node_t {
+        node.node().check_state(self.id);
+        node.node().to_ffi()
+    }
+}
+
 /// `JitState` utility methods
 impl<'a> JitState<'a> {
-    pub fn clear_state(&mut self) {
+    /// Emits the machine code for this state. The resulting `CompiledCode`
+    /// owns the executable memory, which stays valid after this state is
+    /// cleared or dropped.
+    ///
+    /// Fails if lightning cannot map memory for the code, if the buffer
+    /// given to `set_code` is too small, or if the code would exceed the
+    /// quota of the `Jit`; the error gives the state back.
+    pub fn emit(self) -> EmitResult<'a> {
+        if let Err(e) = self.check_quota() {
+            return Err(EmitError::new(e, self));
+        }
+        let entry = unsafe { bindings::_jit_emit(*self.raw()) };
+        if entry.is_null() {
+            return Err(EmitError::new(Error::EmitFailed, self));
+        }
+        Ok(self.emitted(entry))
+    }
+
+    /// Emits the machine code for this state into memory provided by
+    /// `buffer`, asking it for more if the code does not fit. The memory must
+    /// stay valid for as long as the code lives; see `CodeBuffer`.
+    ///
+    /// Fails if `buffer` runs out of memory, or if the code would exceed the
+    /// quota of the `Jit`; the error gives the state back, which no longer
+    /// refers to the memory of `buffer`.
+    pub fn emit_into(self, buffer: &mut impl CodeBuffer) -> EmitResult<'a> {
+        let mut size = 0;
         unsafe {
-            bindings::_jit_clear_state(self.state);
+            bindings::lgsys_realize(*self.raw());
+            bindings::_jit_get_code(*self.raw(), &mut size);
         }
+        self.emit_sized(buffer, size as usize)
     }
 
-    // there is no way to require a function type in a trait bound
-    // without specifying the number of arguments
-    pub unsafe fn cast_emit<T: Copy>(&mut self) -> T {
-        *(&self.emit() as *const *mut core::ffi::c_void as *const T)
+    /// Emits into `buffer`, asking it for `size` bytes first.
+    pub(crate) fn emit_sized(self, buffer: &mut impl CodeBuffer, size: usize) -> EmitResult<'a> {
+        if let Err(e) = self.check_quota() {
+            return Err(EmitError::new(e, self));
+        }
+        match self.emit_attempts(buffer, size) {
+            Ok(entry) => Ok(self.emitted(entry)),
+            Err(e) => {
+                // Emitting the state again must not write to `buffer`.
+                unsafe { bindings::lgsys_forget_code(*self.raw()) };
+                Err(EmitError::new(e, self))
+            }
+        }
     }
 
-    jit_reexport!(_jit_emit, emit; -> JitPointer);
+    /// Emits into regions of `buffer` until the code fits, returning its
+    /// entry point.
+    fn emit_attempts(&self, buffer: &mut impl CodeBuffer, mut size: usize) -> Result<JitPointer> {
+        loop {
+            let region = buffer.reserve(size).ok_or(Error::CodeBufferFull { size })?;
+            let len = region.len();
+            if len < size {
+                return Err(Error::CodeBufferFull { size });
+            }
+            unsafe { bindings::_jit_set_code(*self.raw(), region.as_mut_ptr() as JitPointer, len as JitWord) };
 
-    jit_reexport!(_jit_address, address, node: &JitNode<'a>; -> JitPointer);
+            let entry = unsafe { bindings::_jit_emit(*self.raw()) };
+            if !entry.is_null() {
+                return Ok(entry);
+            }
+            // Each attempt asks for twice as much, so this ends once the
+            // buffer runs out or the size overflows.
+            size = len.checked_mul(2).ok_or(Error::CodeBufferFull { size: len })?;
+        }
+    }
 
-    jit_reexport!(_jit_forward_p, forward_p, node: &JitNode<'a>; -> bool);
-    jit_reexport!(_jit_indirect_p, indirect_p, node: &JitNode<'a>; -> bool);
-    jit_reexport!(_jit_target_p, target_p, node: &JitNode<'a>; -> bool);
-    jit_reexport!(_jit_arg_register_p, arg_register_p, node: &JitNode<'a>; -> bool);
+    /// Fails if the code of this state would take the memory used by its
+    /// `Jit` past the quota.
+    fn check_quota(&self) -> Result<()> {
+        let (mut code, mut data, mut notes) = (0, 0, 0);
+        unsafe {
+            bindings::lgsys_realize(*self.raw());
+            bindings::_jit_get_code(*self.raw(), &mut code);
+            bindings::lgsys_data_sizes(*self.raw(), &mut data, &mut notes);
+        }
+        self.accounting.check(code as usize, data as usize, notes as usize)
+    }
+
+    /// Takes the code emitted at `entry`, and counts it against the `Jit`:
+    /// the whole memory holding the code, which is what lightning estimated
+    /// when the quota was checked.
+    fn emitted(mut self, entry: JitPointer) -> JitState<'a, Emitted> {
+        let mut code = unsafe { CompiledCode::take(self.state, self.id, entry) };
+        code.storage = self.storage.take();
+        let (code_size, data_len, note_len) = (code.code_size(), code.data_bytes().len(), code.note_bytes().len());
+        code.charge = Some(Charge::new(&self.accounting, code_size, data_len, note_len));
+        self.into_phase(|Building| Emitted { code })
+    }
+
+    /// Emits the machine code for this state, treating its entry point as a
+    /// function of type `T`.
+    ///
+    /// # Safety
+    /// `T` must match the signature of the first function in this state.
+    pub unsafe fn cast_emit<T: JitFnPtr>(self) -> EmitResult<'a, Emitted<T>> {
+        Ok(self.emit()?.into_phase(|e| Emitted { code: e.code.cast() }))
+    }
+
+    pub fn forward_p(&mut self, node: &impl Node<'a>) -> bool {
+        unsafe { bindings::_jit_forward_p(*self.raw(), self.raw_node(node)) != 0 }
+    }
+
+    pub fn indirect_p(&mut self, node: &impl Node<'a>) -> bool {
+        unsafe { bindings::_jit_indirect_p(*self.raw(), self.raw_node(node)) != 0 }
+    }
+
+    pub fn target_p(&mut self, node: &impl Node<'a>) -> bool {
+        unsafe { bindings::_jit_target_p(*self.raw(), self.raw_node(node)) != 0 }
+    }
+
+    pub fn arg_register_p<T>(&mut self, node: &Arg<'a, T>) -> bool {
+        unsafe { bindings::_jit_arg_register_p(*self.raw(), self.raw_node(node)) != 0 }
+    }
     jit_reexport!(_jit_callee_save_p, callee_save_p, reg: Reg; -> bool);
-    jit_reexport!(_jit_pointer_p, pointer_p, ptr: JitPointer; -> bool);
 
-    jit_reexport!(_jit_patch, patch, instr: &JitNode<'a>);
-    jit_reexport!(_jit_patch_at, patch_at, instr: &JitNode<'a>, target: &JitNode<'a>);
-    jit_reexport!(_jit_patch_abs, patch_abs, instr: &JitNode<'a>, target: JitPointer);
+    pub fn patch(&mut self, instr: &impl Patchable<'a>) {
+        unsafe { bindings::_jit_patch(*self.raw(), self.raw_node(instr)) }
+    }
+
+    pub fn patch_at(&mut self, instr: &impl Patchable<'a>, target: &impl Target<'a>) {
+        unsafe { bindings::_jit_patch_at(*self.raw(), self.raw_node(instr), self.raw_node(target)) }
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // `target` is only stored
+    pub fn patch_abs(&mut self, instr: &impl Patchable<'a>, target: JitPointer) {
+        unsafe { bindings::_jit_patch_abs(*self.raw(), self.raw_node(instr), target) }
+    }
+
     jit_reexport!(_jit_realize, realize);
 
     // get_code needs argument mangling that jit_reexport currently does not
     // provide
     pub fn get_code(&self, code_size: Option<&mut JitWord>) -> JitPointer {
-        unsafe { bindings::_jit_get_code(self.state, pointer_from(code_size)) }
+        unsafe { bindings::_jit_get_code(*self.raw(), pointer_from(code_size)) }
     }
 
-    jit_reexport!(_jit_set_code, set_code, buf: JitPointer, size: JitWord);
+    /// Makes `emit` place the code into `buf` instead of mapping memory
+    /// itself. Fails if `buf` is null or `size` is not positive.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // `buf` is only stored
+    pub fn set_code(&mut self, buf: JitPointer, size: JitWord) -> Result<()> {
+        if buf.is_null() || size <= 0 {
+            return Err(Error::InvalidBuffer);
+        }
+        unsafe { bindings::_jit_set_code(*self.raw(), buf, size) };
+        Ok(())
+    }
 
     // get_data needs argument mangling that jit_reexport currently does not
     // provide
@@ -103,7 +313,7 @@ impl<'a> JitState<'a> {
     ) -> JitPointer {
         unsafe {
             bindings::_jit_get_data(
-                self.state,
+                *self.raw(),
                 pointer_from(data_size),
                 pointer_from(note_size),
             )
@@ -111,52 +321,323 @@ impl<'a> JitState<'a> {
     }
 
     jit_reexport!(_jit_set_data, set_data, buf: JitPointer, data_size: JitWord, flags: JitWord);
+}
 
+/// Methods available until the state is cleared
+impl<'a, P: Uncleared> JitState<'a, P> {
     jit_reexport!(_jit_print, print);
+
+    /// Writes the listing that `print` sends to the C `stdout` to `out`
+    /// instead.
+    ///
+    /// The listing is also available through `Display`, where the alternate
+    /// form (`{:#}`) gives the normalized listing described by
+    /// `normalize_listing`.
+    pub fn print_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.listing()?)
+    }
+
+    fn listing(&self) -> io::Result<Vec<u8>> {
+        capture(|length| unsafe { bindings::lgsys_print(*self.raw(), length) })
+    }
+}
+
+/// Runs one of the `lgsys_print` family of helpers, which return a buffer that
+/// we must free, while nothing else redirects the output of the C library.
+fn capture(print: impl FnOnce(&mut JitWord) -> *mut c_char) -> io::Result<Vec<u8>> {
+    let _guard = PRINT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut length = 0;
+    let buffer = print(&mut length);
+    if buffer.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let listing = std::slice::from_raw_parts(buffer as *const u8, length as usize).to_vec();
+        bindings::lgsys_free(buffer as JitPointer);
+        Ok(listing)
+    }
+}
+
+impl<'a, P: Uncleared> fmt::Display for JitState<'a, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let listing = self.listing().map_err(|_| fmt::Error)?;
+        let listing = String::from_utf8_lossy(&listing);
+        if f.alternate() {
+            f.write_str(&normalize_listing(&listing))
+        } else {
+            f.write_str(&listing)
+        }
+    }
+}
+
+/// Replaces the addresses in a listing produced by `print_to` with numbers
+/// that do not change from one run to the next.
+///
+/// Only the operands that lightning prints as addresses are replaced: the
+/// targets of `jmpi`, `calli`, `finishi` and branches that do not go to a
+/// label, and the addresses of `ldi` and `sti`. Each distinct address is
+/// replaced by `@1`, `@2`, ... in the order of its first appearance, so that a
+/// listing still shows which operands refer to the same place. Immediates are
+/// kept, so listings that differ in a constant still differ, and labels are
+/// already printed as stable `L` numbers by lightning.
+pub fn normalize_listing(listing: &str) -> String {
+    let mut seen: Vec<&str> = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in listing.split('\n') {
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        let mut address = None;
+        let mut operand = 0;
+
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let len = rest[start..].find(char::is_whitespace).unwrap_or(rest.len() - start);
+            let token = &rest[start..start + len];
+            out.push_str(&rest[..start]);
+            rest = &rest[start + len..];
+
+            if operand == 0 {
+                address = address_operand(token);
+                out.push_str(token);
+            } else if address == Some(operand) && token.starts_with("0x") {
+                let index = seen.iter().position(|&t| t == token).unwrap_or_else(|| {
+                    seen.push(token);
+                    seen.len() - 1
+                });
+                out.push('@');
+                out.push_str(&(index + 1).to_string());
+            } else {
+                out.push_str(token);
+            }
+            operand += 1;
+        }
+
+        out.push_str(rest);
+        lines.push(out);
+    }
+    lines.join("\n")
+}
+
+/// Returns which operand of `mnemonic`, counting from 1, is an address.
+fn address_operand(mnemonic: &str) -> Option<usize> {
+    match mnemonic {
+        "jmpi" | "calli" | "finishi" => Some(1),
+        _ if mnemonic.starts_with("ldi_") => Some(2),
+        _ if mnemonic.starts_with("sti_") => Some(1),
+        _ if mnemonic.starts_with('b') && !mnemonic.starts_with("bswap") => Some(1),
+        _ => None,
+    }
+}
+
+/// Methods of an emitted state
+impl<'a, F> JitState<'a, Emitted<F>> {
+    /// Returns the emitted code.
+    pub fn code(&self) -> &CompiledCode<F> {
+        &self.phase.code
+    }
+
+    pub fn address(&self, node: &impl Addressable<'a>) -> JitPointer {
+        unsafe { bindings::_jit_address(*self.raw(), self.raw_node(node)) }
+    }
+
+    /// Returns the offsets within `code_bytes` of the function at `node`: from
+    /// its address up to the next `name`, or to the end of the code.
+    pub fn function_range(&self, node: &impl Addressable<'a>) -> Range<usize> {
+        let base = self.code_addr();
+        let start = CodeAddr::from(self.address(node)) - base;
+        let len = self.code_bytes().len();
+        let end = self
+            .notes()
+            .functions()
+            .iter()
+            .map(|f| (f.start - usize::from(base), f.size))
+            .find(|&(at, size)| at <= start && start < at + size)
+            .map_or(len, |(at, size)| len.min(at + size));
+        start..end
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // `ptr` is only compared
+    pub fn pointer_p(&self, ptr: JitPointer) -> bool {
+        unsafe { bindings::_jit_pointer_p(*self.raw(), ptr) != 0 }
+    }
+
+    /// Looks up the note that covers `code`, returning the function name,
+    /// file name and line number it records.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // `code` is only compared
+    #[allow(clippy::type_complexity)]
+    pub fn get_note(&self, code: JitPointer) -> Result<(Option<&CStr>, Option<&CStr>, i32)> {
+        let mut name = std::ptr::null_mut();
+        let mut file = std::ptr::null_mut();
+        let mut line = 0;
+        let found = unsafe {
+            bindings::_jit_get_note(*self.raw(), code, &mut name, &mut file, &mut line) != 0
+        };
+        if !found {
+            return Err(Error::NoNote { address: code as usize });
+        }
+        // The strings live in the data buffer, which the emitted code owns.
+        let to_c_str = |p: *mut std::os::raw::c_char| {
+            if p.is_null() { None } else { Some(unsafe { CStr::from_ptr(p) }) }
+        };
+        Ok((to_c_str(name), to_c_str(file), line))
+    }
+
+    /// Returns the source location of the code at `addr`, as recorded by
+    /// `name` and `note`, or `None` if no note covers `addr`.
+    pub fn lookup(&self, addr: JitPointer) -> Option<NoteInfo<'_>> {
+        self.notes().lookup(addr)
+    }
+
+    /// Returns an iterator over every line recorded by `note`.
+    pub fn notes(&self) -> Notes<'_> {
+        unsafe { Notes::new(self.state) }
+    }
+
+    /// Releases the nodes of this state, which can no longer be queried
+    /// afterwards.
+    pub fn clear_state(self) -> JitState<'a, Cleared<F>> {
+        unsafe {
+            bindings::_jit_clear_state(*self.raw());
+        }
+        self.into_phase(|e| Cleared { code: e.code })
+    }
+
+    /// Clears and destroys this state, keeping only the code.
+    pub fn into_code(self) -> CompiledCode<F> {
+        self.clear_state().into_code()
+    }
+}
+
+/// Disassembly of an emitted state, with the `disassembler` feature
+#[cfg(feature = "disassembler")]
+impl<'a, F> JitState<'a, Emitted<F>> {
+    /// Prints the disassembly of the emitted code to `stdout`.
+    ///
+    /// This prints nothing if libopcodes was not found when lightning-sys was
+    /// built; see `disassembly`.
+    pub fn disassemble(&self) {
+        unsafe { bindings::_jit_disassemble(*self.raw()) }
+    }
+
+    /// Returns the disassembly that `disassemble` prints, with a line of the
+    /// form `# name file:line` before the first instruction covered by each
+    /// `name` and `note`.
+    ///
+    /// Fails if libopcodes was not found when lightning-sys was built.
+    #[allow(clippy::io_other_error)] // `io::Error::other` needs Rust 1.74
+    pub fn disassembly(&self) -> io::Result<String> {
+        if unsafe { bindings::lgsys_has_disassembler() } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "lightning-sys was built without libopcodes",
+            ));
+        }
+
+        let raw = {
+            // The disassembler writes to file descriptor 1 while this runs,
+            // so keep Rust's output out of the way.
+            let stdout = io::stdout();
+            let _lock = stdout.lock();
+            capture(|length| unsafe { bindings::lgsys_disassemble(*self.raw(), length) })?
+        };
+
+        let mut out = String::new();
+        let mut last = None;
+        for line in String::from_utf8_lossy(&raw).lines() {
+            let address = line
+                .split_whitespace()
+                .next()
+                .map(|word| word.trim_end_matches(':'))
+                .filter(|word| word.starts_with("0x"))
+                .and_then(|word| usize::from_str_radix(&word[2..], 16).ok());
+            if let Some(address) = address {
+                let note = self.get_note(address as JitPointer).ok();
+                if note != last {
+                    if let Some((name, file, line)) = note {
+                        let text = |s: Option<&CStr>| {
+                            s.map_or_else(|| "?".into(), |s| s.to_string_lossy().into_owned())
+                        };
+                        out.push_str(&format!("# {} {}:{}\n", text(name), text(file), line));
+                    }
+                    last = note;
+                }
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// Methods of a cleared state
+impl<'a, F> JitState<'a, Cleared<F>> {
+    /// Returns the emitted code.
+    pub fn code(&self) -> &CompiledCode<F> {
+        &self.phase.code
+    }
+
+    /// Destroys this state, keeping only the code.
+    pub fn into_code(self) -> CompiledCode<F> {
+        let mut code = None;
+        // dropping the remaining state destroys it
+        let _: JitState<'a, ()> = self.into_phase(|c| code = Some(c.code));
+        code.expect("phase is always moved")
+    }
+}
+
+impl<'a, F> Deref for JitState<'a, Emitted<F>> {
+    type Target = CompiledCode<F>;
+
+    fn deref(&self) -> &CompiledCode<F> {
+        &self.phase.code
+    }
+}
+
+impl<'a, F> Deref for JitState<'a, Cleared<F>> {
+    type Target = CompiledCode<F>;
+
+    fn deref(&self) -> &CompiledCode<F> {
+        &self.phase.code
+    }
 }
 
 /// implementations of word-size-dependent aliases and exports
 impl<'a> JitState<'a> {
-    #[cfg(target_pointer_width = "64")]
-    jit_alias!(getarg_l => getarg, reg: Reg, node: &JitNode<'a>);
-    #[cfg(target_pointer_width = "32")]
-    jit_alias!(getarg_i => getarg, reg: Reg, node: &JitNode<'a>);
-
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(ldr_i => ldr, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(ldr_i => ldr, targ: Reg, src: Base; -> JitNode<'a>);
     #[cfg(target_pointer_width = "32")]
     jit_alias!(ldi_i => ldi, targ: Reg, src: JitPointer; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(ldr_l => ldr, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(ldr_l => ldr, targ: Reg, src: Base; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
     jit_alias!(ldi_l => ldi, targ: Reg, src: JitPointer; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(ldxr_i => ldxr, targ: Reg, a: Reg, b: Reg; -> JitNode<'a>);
+    jit_alias!(ldxr_i => ldxr, targ: Reg, a: Base, b: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(ldxi_i => ldxi, targ: Reg, src: Reg, off: JitWord; -> JitNode<'a>);
+    jit_alias!(ldxi_i => ldxi, targ: Reg, src: Base, off: JitWord; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(ldxr_l => ldxr, targ: Reg, a: Reg, b: Reg; -> JitNode<'a>);
+    jit_alias!(ldxr_l => ldxr, targ: Reg, a: Base, b: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(ldxi_l => ldxi, targ: Reg, src: Reg, off: JitWord; -> JitNode<'a>);
+    jit_alias!(ldxi_l => ldxi, targ: Reg, src: Base, off: JitWord; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(str_i => str, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(str_i => str, targ: Base, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "32")]
     jit_alias!(sti_i => sti, targ: JitPointer, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(str_l => str, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(str_l => str, targ: Base, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
     jit_alias!(sti_i => sti, targ: JitPointer, src: Reg; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(stxr_i => stxr, off: Reg, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(stxr_i => stxr, targ: Base, off: Reg, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(stxi_i => stxi, off: JitWord, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(stxi_i => stxi, off: JitWord, targ: Base, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(stxr_l => stxr, off: Reg, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(stxr_l => stxr, targ: Base, off: Reg, src: Reg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(stxi_l => stxi, off: JitWord, targ: Reg, src: Reg; -> JitNode<'a>);
+    jit_alias!(stxi_l => stxi, off: JitWord, targ: Base, src: Reg; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "32")]
     jit_alias!(retval_i => retval, rv: Reg);
@@ -164,19 +645,19 @@ impl<'a> JitState<'a> {
     jit_alias!(retval_l => retval, rv: Reg);
 
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(truncr_f_i => truncr_f, int: Reg, float: Reg; -> JitNode<'a>);
+    jit_alias!(truncr_f_i => truncr_f, int: Reg, float: FReg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(truncr_f_l => truncr_f, int: Reg, float: Reg; -> JitNode<'a>);
+    jit_alias!(truncr_f_l => truncr_f, int: Reg, float: FReg; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "32")]
-    jit_alias!(truncr_d_i => truncr_d, int: Reg, float: Reg; -> JitNode<'a>);
+    jit_alias!(truncr_d_i => truncr_d, int: Reg, float: FReg; -> JitNode<'a>);
     #[cfg(target_pointer_width = "64")]
-    jit_alias!(truncr_d_l => truncr_d, int: Reg, float: Reg; -> JitNode<'a>);
+    jit_alias!(truncr_d_l => truncr_d, int: Reg, float: FReg; -> JitNode<'a>);
 
     #[cfg(target_pointer_width = "64")]
-    jit_reexport!(_jit_getarg_ui, getarg_ui, reg: Reg, node: &JitNode<'a>);
+    jit_reexport!(_jit_getarg_ui, getarg_ui, reg: Reg, node: &Arg<'a, u32>);
     #[cfg(target_pointer_width = "64")]
-    jit_reexport!(_jit_getarg_l, getarg_l, reg: Reg, node: &JitNode<'a>);
+    jit_reexport!(_jit_getarg_l, getarg_l, reg: Reg, node: &Arg<'a, i64>);
     #[cfg(target_pointer_width = "64")]
     jit_reexport!(_jit_retval_ui, retval_ui, rv: Reg);
     #[cfg(target_pointer_width = "64")]
@@ -186,53 +667,93 @@ impl<'a> JitState<'a> {
 /// implementations of general instructions
 impl<'a> JitState<'a> {
 
-    pub fn name(&mut self, name: Option<&str>) -> JitNode<'a> {
-        // I looked at the lightning code, this will be copied
-        let cs = name
-            .map(CString::new)
-            .map(Result::unwrap)
-            .map(|c| c.as_ptr())
-            .unwrap_or(core::ptr::null());
-        JitNode{
-            node: unsafe { bindings::_jit_name(self.state, cs) },
+    pub fn name(&mut self, name: Option<&str>) -> Result<Note<'a>> {
+        // lightning copies the string, so it only has to outlive the call
+        let cs = name.map(CString::new).transpose()?;
+        Ok(Note(JitNode{
+            node: unsafe { bindings::_jit_name(*self.raw(), c_str_or_null(&cs)) },
+            state: self.id,
             phantom: std::marker::PhantomData,
-        }
+        }))
     }
 
-    pub fn note(&mut self, file: Option<&str>, line: u32) -> JitNode<'a> {
-        // I looked at the lightning code, this will be copied
-        let cs = file
-            .map(CString::new)
-            .map(Result::unwrap)
-            .map(|c| c.as_ptr())
-            .unwrap_or(core::ptr::null());
-        JitNode{
-            node: unsafe { bindings::_jit_note(self.state, cs, line as i32) },
+    pub fn note(&mut self, file: Option<&str>, line: u32) -> Result<Note<'a>> {
+        // lightning copies the string, so it only has to outlive the call
+        let cs = file.map(CString::new).transpose()?;
+        Ok(Note(JitNode{
+            node: unsafe { bindings::_jit_note(*self.raw(), c_str_or_null(&cs), line as i32) },
+            state: self.id,
             phantom: std::marker::PhantomData,
-        }
+        }))
     }
 
-    jit_reexport!(_jit_label, label; -> JitNode<'a>);
-    jit_reexport!(_jit_forward, forward; -> JitNode<'a>);
-    jit_reexport!(_jit_indirect, indirect; -> JitNode<'a>);
-    jit_reexport!(_jit_link, link, node: &JitNode<'a>);
+    jit_reexport!(_jit_label, label; -> Label<'a>);
+    jit_reexport!(_jit_forward, forward; -> Forward<'a>);
+    jit_reexport!(_jit_indirect, indirect; -> Label<'a>);
+    jit_reexport!(_jit_link, link, node: &Forward<'a>);
+
+    pub fn prolog(&mut self) {
+        unsafe { bindings::_jit_prolog(*self.raw()) };
+        self.allocai.push(0);
+    }
 
-    jit_reexport!(_jit_prolog, prolog);
     jit_reexport!(_jit_ellipsis, ellipsis);
 
-    jit_reexport!(_jit_allocai, allocai, size: i32; -> i32);
+    pub fn allocai(&mut self, size: i32) -> i32 {
+        let offset = unsafe { bindings::_jit_allocai(*self.raw(), size) };
+        if let Some(total) = self.allocai.last_mut() {
+            *total += size as usize;
+        }
+        offset
+    }
+
     jit_reexport!(_jit_allocar, allocar, off: Reg, size: Reg);
 
-    jit_reexport!(_jit_arg, arg; -> JitNode<'a>);
+    /// Declares an argument of type `T`, using the `arg` variant for `T`.
+    pub fn arg<T: FFISafe>(&mut self) -> Arg<'a, T> {
+        T::arg(self)
+    }
+
+    /// Reads `arg` into `reg`, using the `getarg` variant for `T`.
+    pub fn getarg<T: FFISafe>(&mut self, reg: T::Reg, arg: &Arg<'a, T>) {
+        T::getarg(self, reg, arg)
+    }
+
+    /// Replaces `arg` with the value in `reg`, using the `putargr` variant
+    /// for `T`.
+    pub fn putargr<T: FFISafe>(&mut self, reg: T::Reg, arg: &Arg<'a, T>) {
+        T::putargr(self, reg, arg)
+    }
 
-    jit_reexport!(_jit_getarg_c, getarg_c, reg: Reg, node: &JitNode<'a>);
-    jit_reexport!(_jit_getarg_uc, getarg_uc, reg: Reg, node: &JitNode<'a>);
-    jit_reexport!(_jit_getarg_s, getarg_s, reg: Reg, node: &JitNode<'a>);
-    jit_reexport!(_jit_getarg_us, getarg_us, reg: Reg, node: &JitNode<'a>);
-    jit_reexport!(_jit_getarg_i, getarg_i, reg: Reg, node: &JitNode<'a>);
+    /// Replaces `arg` with `imm`, using the `putargi` variant for `T`.
+    pub fn putargi<T: FFISafe>(&mut self, imm: T, arg: &Arg<'a, T>) {
+        T::putargi(self, imm, arg)
+    }
 
-    jit_reexport!(_jit_putargr, putargr, reg: Reg, arg: &JitNode<'a>);
-    jit_reexport!(_jit_putargi, putargi, imm: JitWord, arg: &JitNode<'a>);
+    /// Declares an integer or pointer argument.
+    pub(crate) fn arg_word<T>(&mut self) -> Arg<'a, T> {
+        Arg::new(jit_reexport!(@node self, _jit_arg))
+    }
+
+    /// Reads the integer or pointer argument `arg` with the `getarg` variant
+    /// `getarg`.
+    pub(crate) fn getarg_with<T>(&mut self, getarg: GetargFn, reg: Reg, arg: &Arg<'a, T>) {
+        unsafe { getarg(*self.raw(), reg.to_ffi(), self.raw_node(arg)) }
+    }
+
+    pub(crate) fn putargr_word<T>(&mut self, reg: Reg, arg: &Arg<'a, T>) {
+        unsafe { bindings::_jit_putargr(*self.raw(), reg.to_ffi(), self.raw_node(arg)) }
+    }
+
+    pub(crate) fn putargi_word<T>(&mut self, imm: JitWord, arg: &Arg<'a, T>) {
+        unsafe { bindings::_jit_putargi(*self.raw(), imm, self.raw_node(arg)) }
+    }
+
+    jit_reexport!(_jit_getarg_c, getarg_c, reg: Reg, node: &Arg<'a, i8>);
+    jit_reexport!(_jit_getarg_uc, getarg_uc, reg: Reg, node: &Arg<'a, u8>);
+    jit_reexport!(_jit_getarg_s, getarg_s, reg: Reg, node: &Arg<'a, i16>);
+    jit_reexport!(_jit_getarg_us, getarg_us, reg: Reg, node: &Arg<'a, u16>);
+    jit_reexport!(_jit_getarg_i, getarg_i, reg: Reg, node: &Arg<'a, i32>);
 
     jit_reexport!(_jit_va_push, va_push, arg: Reg);
 
@@ -240,29 +761,11 @@ impl<'a> JitState<'a> {
         self.subr(a, c, b)
     }
 
-    pub fn get_note(
-        &self,
-        code: JitPointer,
-        name: Option<&mut * mut std::os::raw::c_char>,
-        file: Option<&mut * mut std::os::raw::c_char>,
-        lineno: Option<&mut bindings::jit_int32_t>,
-    ) -> bool {
-        unsafe {
-            bindings::_jit_get_note(
-                self.state,
-                code,
-                pointer_from(name),
-                pointer_from(file),
-                pointer_from(lineno),
-            ) != 0
-        }
-    }
-
     jit_reexport!(_jit_prepare, prepare);
     jit_reexport!(_jit_pushargr, pushargr, arg: Reg);
     jit_reexport!(_jit_pushargi, pushargi, arg: JitWord);
     jit_reexport!(_jit_finishr, finishr, arg: Reg);
-    jit_reexport!(_jit_finishi, finishi, arg: JitPointer; -> JitNode<'a>);
+    jit_reexport!(_jit_finishi, finishi, arg: JitPointer; -> CallSite<'a>);
     jit_reexport!(_jit_ret, ret);
     jit_reexport!(_jit_retr, retr, rv: Reg);
     jit_reexport!(_jit_reti, reti, rv: JitWord);
@@ -279,38 +782,44 @@ impl<'a> JitState<'a> {
 
 /// implementations of 32-bit float instructions
 impl<'a> JitState<'a> {
-    jit_reexport!(_jit_arg_f, arg_f; -> JitNode<'a>);
-    jit_reexport!(_jit_getarg_f, getarg_f, reg: Reg, arg: &JitNode<'a>);
-    jit_reexport!(_jit_putargr_f, putargr_f, reg: Reg, arg: &JitNode<'a>);
-    jit_reexport!(_jit_putargi_f, putargi_f, imm: f32, arg: &JitNode<'a>);
+    pub fn arg_f(&mut self) -> Arg<'a, f32> {
+        Arg::new(jit_reexport!(@node self, _jit_arg_f))
+    }
 
-    pub fn rsbr_f(&mut self, a: Reg, b: Reg, c: Reg) -> JitNode<'a> {
+    jit_reexport!(_jit_getarg_f, getarg_f, reg: FReg, arg: &Arg<'a, f32>);
+    jit_reexport!(_jit_putargr_f, putargr_f, reg: FReg, arg: &Arg<'a, f32>);
+    jit_reexport!(_jit_putargi_f, putargi_f, imm: f32, arg: &Arg<'a, f32>);
+
+    pub fn rsbr_f(&mut self, a: FReg, b: FReg, c: FReg) -> JitNode<'a> {
         self.subr_f(a, c, b)
     }
 
-    jit_reexport!(_jit_pushargr_f, pushargr_f, reg: Reg);
+    jit_reexport!(_jit_pushargr_f, pushargr_f, reg: FReg);
     jit_reexport!(_jit_pushargi_f, pushargi_f, imm: f32);
-    jit_reexport!(_jit_retr_f, retr_f, reg: Reg);
+    jit_reexport!(_jit_retr_f, retr_f, reg: FReg);
     jit_reexport!(_jit_reti_f, reti_f, imm: f32);
-    jit_reexport!(_jit_retval_f, retval_f, reg: Reg);
+    jit_reexport!(_jit_retval_f, retval_f, reg: FReg);
 }
 
 /// implementations of 64-bit float instructions
 impl<'a> JitState<'a> {
-    jit_reexport!(_jit_arg_d, arg_d; -> JitNode<'a>);
-    jit_reexport!(_jit_getarg_d, getarg_d, reg: Reg, arg: &JitNode<'a>);
-    jit_reexport!(_jit_putargr_d, putargr_d, reg: Reg, arg: &JitNode<'a>);
-    jit_reexport!(_jit_putargi_d, putargi_d, imm: f64, arg: &JitNode<'a>);
+    pub fn arg_d(&mut self) -> Arg<'a, f64> {
+        Arg::new(jit_reexport!(@node self, _jit_arg_d))
+    }
 
-    pub fn rsbr_d(&mut self, a: Reg, b: Reg, c: Reg) -> JitNode<'a> {
+    jit_reexport!(_jit_getarg_d, getarg_d, reg: FReg, arg: &Arg<'a, f64>);
+    jit_reexport!(_jit_putargr_d, putargr_d, reg: FReg, arg: &Arg<'a, f64>);
+    jit_reexport!(_jit_putargi_d, putargi_d, imm: f64, arg: &Arg<'a, f64>);
+
+    pub fn rsbr_d(&mut self, a: FReg, b: FReg, c: FReg) -> JitNode<'a> {
         self.subr_d(a, c, b)
     }
 
-    jit_reexport!(_jit_pushargr_d, pushargr_d, reg: Reg);
+    jit_reexport!(_jit_pushargr_d, pushargr_d, reg: FReg);
     jit_reexport!(_jit_pushargi_d, pushargi_d, imm: f64);
-    jit_reexport!(_jit_retr_d, retr_d, reg: Reg);
+    jit_reexport!(_jit_retr_d, retr_d, reg: FReg);
     jit_reexport!(_jit_reti_d, reti_d, imm: f64);
-    jit_reexport!(_jit_retval_d, retval_d, reg: Reg);
+    jit_reexport!(_jit_retval_d, retval_d, reg: FReg);
 }
 
 /// Defines an inherent method for `JitState` for each `jit_entry` that
@@ -330,7 +839,8 @@ macro_rules! private_make_func {
 }
 
 macro_rules! mm {
-    ( ( $entry:ident ( $( $inarg:ident ),* ) $root:ident ) => ( $( $types:ty ),* ) => ( $( $outarg:ident ),* ) ) => {
+    ( $a:tt => $types:tt => $outargs:tt ) => { mm!{ $a => $types -> JitNode => $outargs } };
+    ( ( $entry:ident ( $( $inarg:ident ),* ) $root:ident ) => ( $( $types:ty ),* ) -> JitNode => ( $( $outarg:ident ),* ) ) => {
         make_func! {
             func = [{ $root }]
             body = [{
@@ -344,13 +854,40 @@ macro_rules! mm {
             parmtypes = [{ $( $types ),* }]
         }
     };
+    ( ( $entry:ident ( $( $inarg:ident ),* ) $root:ident ) => ( $( $types:ty ),* ) -> $kind:ident => ( $( $outarg:ident ),* ) ) => {
+        make_func! {
+            func = [{ $root }]
+            body = [{
+                $kind(unsafe {
+                    self.$entry( $( $inarg.to_ffi().into() ),* )
+                })
+            }]
+            rettype = [{ $kind<'j> }]
+            parmhead = [{ &mut self, }]
+            parmnames = [{ $( $inarg ),* }]
+            parmtypes = [{ $( $types ),* }]
+        }
+    };
 }
 
-/// Infer immediate type
-macro_rules! it {
-    ( _d ) => { f64 };
-    ( _f ) => { f32 };
-    (    ) => { JitWord };
+/// Selects the first signature for floating-point comparisons, which write an
+/// integer result, and the second for every other floating-point instruction.
+macro_rules! fcmp {
+    ( lt    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( le    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( eq    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( ge    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( gt    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( ne    ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( unlt  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( unle  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( uneq  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( unge  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( ungt  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( ltgt  ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( ord   ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( unord ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $cmp => $r } };
+    ( $q:tt ? $a:tt => $cmp:tt : $other:tt => $r:tt ) => { mm!{ $a => $other => $r } };
 }
 
 macro_rules! jit_inner {
@@ -363,35 +900,76 @@ macro_rules! jit_inner {
 
     // Handlers (by name) --------------------------------------------------------------------------------------
     // Immediate calls and jumps
-    ( $a:tt [ call, i              ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer)         => $r } };
-    ( $a:tt [ jmp, i               ] => $n:tt            $r:tt ) => { mm!{ $a => ()                   => $r } };
-    // All ldi, sti, stxi (ldxi is handled by the jit_new_node_www catch-all)
-    ( $a:tt [ ld, i    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, JitPointer)    => $r } };
-    ( $a:tt [ st, i    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer, Reg)    => $r } };
-    ( $a:tt [ stx, i   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (JitWord, Reg, Reg)  => $r } };
+    ( $a:tt [ call, i              ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer) -> CallSite => $r } };
+    ( $a:tt [ jmp, i               ] => $n:tt            $r:tt ) => { mm!{ $a => ()        -> Branch   => $r } };
+    // Single-register instructions that would otherwise take a word
+    ( $a:tt [ live                 ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg)                 => $r } };
+    ( $a:tt [ va_start             ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg)                 => $r } };
+    ( $a:tt [ va_end               ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg)                 => $r } };
+    // Loads
+    ( $a:tt [ ld, i, _f            ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, JitPointer)    => $r } };
+    ( $a:tt [ ld, i, _d            ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, JitPointer)    => $r } };
+    ( $a:tt [ ld, i    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, JitPointer)     => $r } };
+    ( $a:tt [ ld, r, _f            ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base)          => $r } };
+    ( $a:tt [ ld, r, _d            ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base)          => $r } };
+    ( $a:tt [ ld, r    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, Base)           => $r } };
+    ( $a:tt [ ldx, i, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base, JitWord) => $r } };
+    ( $a:tt [ ldx, i, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base, JitWord) => $r } };
+    ( $a:tt [ ldx, i   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, Base, JitWord)  => $r } };
+    ( $a:tt [ ldx, r, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base, Reg)     => $r } };
+    ( $a:tt [ ldx, r, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Base, Reg)     => $r } };
+    ( $a:tt [ ldx, r   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, Base, Reg)      => $r } };
+    // Stores
+    ( $a:tt [ st, i, _f            ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer, FReg)    => $r } };
+    ( $a:tt [ st, i, _d            ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer, FReg)    => $r } };
+    ( $a:tt [ st, i    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (JitPointer, Reg)     => $r } };
+    ( $a:tt [ st, r, _f            ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, FReg)          => $r } };
+    ( $a:tt [ st, r, _d            ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, FReg)          => $r } };
+    ( $a:tt [ st, r    $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, Reg)           => $r } };
+    ( $a:tt [ stx, i, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (JitWord, Base, FReg) => $r } };
+    ( $a:tt [ stx, i, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (JitWord, Base, FReg) => $r } };
+    ( $a:tt [ stx, i   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (JitWord, Base, Reg)  => $r } };
+    ( $a:tt [ stx, r, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, Reg, FReg)     => $r } };
+    ( $a:tt [ stx, r, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, Reg, FReg)     => $r } };
+    ( $a:tt [ stx, r   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Base, Reg, Reg)      => $r } };
     // Movs
-    ( $a:tt [ mov, i   $(, $y:tt)? ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, it!{$($y)?})   => $r } };
+    ( $a:tt [ mov, i, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, f32)           => $r } };
+    ( $a:tt [ mov, i, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, f64)           => $r } };
+    ( $a:tt [ mov, i               ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, JitWord)        => $r } };
+    // Conversions between register classes
+    ( $a:tt [ ext, r, _f           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Reg)           => $r } };
+    ( $a:tt [ ext, r, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Reg)           => $r } };
+    ( $a:tt [ ext, r, _f_d         ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, FReg)          => $r } };
+    ( $a:tt [ ext, r, _d_f         ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, FReg)          => $r } };
+    ( $a:tt [ trunc, r, $y:tt      ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, FReg)           => $r } };
     // Varargs
-    ( $a:tt [ va_arg $(, _d)?      ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, Reg)           => $r } };
+    ( $a:tt [ va_arg               ] => $n:tt            $r:tt ) => { mm!{ $a => (Reg, Reg)            => $r } };
+    ( $a:tt [ va_arg, _d           ] => $n:tt            $r:tt ) => { mm!{ $a => (FReg, Reg)           => $r } };
 
     // Catch-alls (by signature) -------------------------------------------------------------------------------
     // All quad instructions
-    ( $a:tt [ $q:tt, i $(, $y:tt)* ] => jit_new_node_qww $r:tt ) => { mm!{ $a => (i32, i32, Reg, Reg) => $r } };
+    ( $a:tt [ $q:tt, i $(, $y:tt)* ] => jit_new_node_qww $r:tt ) => { mm!{ $a => (Reg, Reg, Reg, JitWord) => $r } };
     ( $a:tt [ $q:tt, r $(, $y:tt)* ] => jit_new_node_qww $r:tt ) => { mm!{ $a => (Reg, Reg, Reg, Reg) => $r } };
     // Branches
-    ( $a:tt [ $q:tt    $(, $y:tt)* ] => jit_new_node_pwd $r:tt ) => { mm!{ $a => (Reg, f64)           => $r } };
-    ( $a:tt [ $q:tt    $(, $y:tt)* ] => jit_new_node_pwf $r:tt ) => { mm!{ $a => (Reg, f32)           => $r } };
-    ( $a:tt [ $q:tt, r $(, $y:tt)* ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (Reg, Reg)           => $r } };
-    ( $a:tt [ $q:tt, i $(, $y:tt)* ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (Reg, JitWord)       => $r } };
-    // All jit_new_node_ww[fd]
-    ( $a:tt [ $q:tt, i, _d         ] => jit_new_node_wwd $r:tt ) => { mm!{ $a => (Reg, Reg, f64)      => $r } };
-    ( $a:tt [ $q:tt, i, _f         ] => jit_new_node_wwf $r:tt ) => { mm!{ $a => (Reg, Reg, f32)      => $r } };
+    ( $a:tt [ $q:tt    $(, $y:tt)* ] => jit_new_node_pwd $r:tt ) => { mm!{ $a => (FReg, f64)    -> Branch => $r } };
+    ( $a:tt [ $q:tt    $(, $y:tt)* ] => jit_new_node_pwf $r:tt ) => { mm!{ $a => (FReg, f32)    -> Branch => $r } };
+    ( $a:tt [ $q:tt, r, _f         ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (FReg, FReg)   -> Branch => $r } };
+    ( $a:tt [ $q:tt, r, _d         ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (FReg, FReg)   -> Branch => $r } };
+    ( $a:tt [ $q:tt, r $(, $y:tt)* ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (Reg, Reg)     -> Branch => $r } };
+    ( $a:tt [ $q:tt, i $(, $y:tt)* ] => jit_new_node_pww $r:tt ) => { mm!{ $a => (Reg, JitWord) -> Branch => $r } };
+    // All jit_new_node_ww[fd]; comparisons produce an integer
+    ( $a:tt [ $q:tt, i, _d         ] => jit_new_node_wwd $r:tt ) => { fcmp!{ $q ? $a => (Reg, FReg, f64) : (FReg, FReg, f64) => $r } };
+    ( $a:tt [ $q:tt, i, _f         ] => jit_new_node_wwf $r:tt ) => { fcmp!{ $q ? $a => (Reg, FReg, f32) : (FReg, FReg, f32) => $r } };
     // All jit_new_node_w+
-    ( $a:tt [ $q:tt, r             ] => jit_new_node_w   $r:tt ) => { mm!{ $a => (Reg)                => $r } };
-    ( $a:tt [ $q:tt                ] => jit_new_node_w   $r:tt ) => { mm!{ $a => (JitWord)            => $r } };
-    ( $a:tt [ $q:tt, r $(, $y:tt)* ] => jit_new_node_ww  $r:tt ) => { mm!{ $a => (Reg, Reg)           => $r } };
-    ( $a:tt [ $q:tt, i $(, $y:tt)? ] => jit_new_node_www $r:tt ) => { mm!{ $a => (Reg, Reg, JitWord)  => $r } };
-    ( $a:tt [ $q:tt, r $(, $y:tt)? ] => jit_new_node_www $r:tt ) => { mm!{ $a => (Reg, Reg, Reg)      => $r } };
+    ( $a:tt [ $q:tt, r             ] => jit_new_node_w   $r:tt ) => { mm!{ $a => (Reg)                 => $r } };
+    ( $a:tt [ $q:tt                ] => jit_new_node_w   $r:tt ) => { mm!{ $a => (JitWord)             => $r } };
+    ( $a:tt [ $q:tt, r, _f         ] => jit_new_node_ww  $r:tt ) => { mm!{ $a => (FReg, FReg)          => $r } };
+    ( $a:tt [ $q:tt, r, _d         ] => jit_new_node_ww  $r:tt ) => { mm!{ $a => (FReg, FReg)          => $r } };
+    ( $a:tt [ $q:tt, r $(, $y:tt)* ] => jit_new_node_ww  $r:tt ) => { mm!{ $a => (Reg, Reg)            => $r } };
+    ( $a:tt [ $q:tt, r, _f         ] => jit_new_node_www $r:tt ) => { fcmp!{ $q ? $a => (Reg, FReg, FReg) : (FReg, FReg, FReg) => $r } };
+    ( $a:tt [ $q:tt, r, _d         ] => jit_new_node_www $r:tt ) => { fcmp!{ $q ? $a => (Reg, FReg, FReg) : (FReg, FReg, FReg) => $r } };
+    ( $a:tt [ $q:tt, i $(, $y:tt)? ] => jit_new_node_www $r:tt ) => { mm!{ $a => (Reg, Reg, JitWord)   => $r } };
+    ( $a:tt [ $q:tt, r $(, $y:tt)? ] => jit_new_node_www $r:tt ) => { mm!{ $a => (Reg, Reg, Reg)       => $r } };
 
     // Fallbacks (generic patterns) ----------------------------------------------------------------------------
     (   ( $entry:ident ( $( $inarg:ident ),* ) $root:ident )
@@ -424,24 +1002,9 @@ macro_rules! jit_entry_for_node {
 }
 
 macro_rules! jit_entry_non_node {
-    {
-        $caller:tt
-        decl = [{ $entry:ident( $( $inarg:ident ),* ) }]
-        root = [{ destroy_state }]
-        parts = [{ $stem:ident $( $suffix:ident )* }]
-        invokes = [{ $invokes:ident( $( $outarg:ident ),* ) }]
-    } => {
-        make_func! {
-            func = [{ $stem }]
-            body = [{ unsafe { self.$entry( $( $inarg ),* ) } }]
-            rettype = [{ () }]
-            parmhead = [{ self, }] // Takes `self` by move, NOT by reference
-            parmnames = [{ }]
-            parmtypes = [{ }]
-        }
-    };
     { $( $tokens:tt )* } => {
-        // Ignore these for now.
+        // Ignore these for now. In particular, `destroy_state` is not exposed,
+        // since `Drop` for `JitState` already takes care of it.
     };
 }
 
@@ -461,6 +1024,8 @@ fn trivial_invocation() {
 
     impl MyDefault for JitWord    { fn default() -> Self { Default::default() } }
     impl MyDefault for Reg        { fn default() -> Self { Reg::R(0)          } }
+    impl MyDefault for FReg       { fn default() -> Self { FReg::F(0)         } }
+    impl MyDefault for Base       { fn default() -> Self { Base::FP           } }
     impl MyDefault for JitPointer { fn default() -> Self { crate::types::NULL } }
 
     macro_rules! jit_entry_for_node {
@@ -496,20 +1061,19 @@ fn trivial_invocation() {
             parts = [{ $stem:ident $( $suffix:ident )* }]
             invokes = [{ $invokes:ident( _jit $( , $outarg:ident )* ) }]
         } => {
+            // `disassemble` is only available on an emitted state, and is
+            // exercised by the `disassembly` test.
             entry_count += 1;
-            // Allow disassembly to be configured out.
-            #[cfg(disassembly)]
-            #[allow(unreachable_code)]
-            #[allow(unused_variables)]
-            {
-                if false {
-                    // We cannot yet actually invoke these, but at least we can
-                    // check that the functions exist and take the right number
-                    // of parameters.
-                    $( let $outarg = unimplemented!(); )*
-                    let _ = $crate::Jit::new().new_state().disassemble( $( $outarg ),* );
-                }
-            }
+        };
+        {
+            $caller:tt
+            decl = [{ $entry:ident( $( $inarg:ident ),* ) }]
+            root = [{ destroy_state }]
+            parts = [{ $stem:ident $( $suffix:ident )* }]
+            invokes = [{ $invokes:ident( _jit $( , $outarg:ident )* ) }]
+        } => {
+            entry_count += 1;
+            // Destruction is handled by `Drop` rather than by a method.
         };
         {
             $caller:tt
@@ -583,3 +1147,317 @@ fn trivial_invocation() {
     assert!(entry_count < 450, "too many entry points were seen");
 }
 
+
+#[test]
+#[should_panic(expected = "node belongs to a different JitState")]
+fn foreign_node() {
+    let mut jit_a = crate::Jit::new();
+    let mut jit_b = crate::Jit::new();
+    let mut a = jit_a.new_state();
+    let mut b = jit_b.new_state();
+
+    let label = a.label();
+    let jump = b.jmpi();
+    b.patch_at(&jump, &label);
+}
+
+#[test]
+fn interior_nul() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+
+    let nul = |s: &str| Error::from(CString::new(s).unwrap_err());
+    assert_eq!(js.name(Some("bad\0name")).unwrap_err(), nul("bad\0name"));
+    assert_eq!(js.note(Some("bad\0file"), 1).unwrap_err(), nul("bad\0file"));
+    assert!(js.note(None, 1).is_ok());
+}
+
+#[test]
+fn invalid_code_buffer() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+    let mut buf = [0u8; 16];
+
+    assert_eq!(js.set_code(crate::NULL, 16), Err(Error::InvalidBuffer));
+    assert_eq!(js.set_code(buf.as_mut_ptr() as JitPointer, 0), Err(Error::InvalidBuffer));
+}
+
+#[test]
+fn note_lookup() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+
+    js.name(Some("answer")).unwrap();
+    let start = js.note(Some("answer.rs"), 7).unwrap();
+    js.prolog();
+    js.reti(42);
+    js.epilog();
+
+    let js = js.emit().unwrap();
+    let (name, file, line) = js.get_note(js.address(&start)).unwrap();
+    assert_eq!(name.map(CStr::to_bytes), Some(&b"answer"[..]));
+    assert_eq!(file.map(CStr::to_bytes), Some(&b"answer.rs"[..]));
+    assert_eq!(line, 7);
+
+    assert_eq!(js.get_note(crate::NULL), Err(Error::NoNote { address: 0 }));
+}
+
+#[test]
+fn listing_to_string() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+
+    js.prolog();
+    js.movi(Reg::R(0), 0x1234);
+    js.calli(normalize_listing as JitPointer);
+    js.calli(normalize_listing as JitPointer);
+    js.retr(Reg::R(0));
+
+    let mut printed = Vec::new();
+    js.print_to(&mut printed).unwrap();
+    let listing = js.to_string();
+    assert_eq!(printed, listing.as_bytes());
+    assert!(listing.contains("movi r0 0x1234"));
+
+    let normalized = format!("{:#}", js);
+    assert_eq!(normalized.matches("calli @1").count(), 2);
+    assert!(!normalized.contains(&format!("{:p}", normalize_listing as JitPointer)));
+}
+
+#[test]
+fn listing_normalization() {
+    let listing = "\tmovi r0 0x10\n\tcalli 0x7f3a10\n\tldi_i r1 0x55d0c0\n\tjmpi 0x7f3a10\n";
+    assert_eq!(
+        normalize_listing(listing),
+        "\tmovi r0 0x10\n\tcalli @1\n\tldi_i r1 @2\n\tjmpi @1\n",
+    );
+}
+
+#[test]
+fn listing_normalization_keeps_immediates() {
+    let listing = "\tbeqi 0x7f3a10 r0 0x12345678\n\taddi r1 r1 0x12345678\n\tsti_l 0x55d0c0 r1\n";
+    assert_eq!(
+        normalize_listing(listing),
+        "\tbeqi @1 r0 0x12345678\n\taddi r1 r1 0x12345678\n\tsti_l @2 r1\n",
+    );
+    assert_ne!(
+        normalize_listing("\tmovi r0 0x12345678\n\tbeqi L1 r0 0x10000\n"),
+        normalize_listing("\tmovi r0 0x87654321\n\tbeqi L1 r0 0x10000\n"),
+    );
+    assert_ne!(
+        normalize_listing("\tbeqi L1 r0 0x10000\n"),
+        normalize_listing("\tbeqi L1 r0 0x20000\n"),
+    );
+}
+
+#[test]
+#[cfg(feature = "disassembler")]
+fn disassembly() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+
+    js.name(Some("answer")).unwrap();
+    js.note(Some("answer.rs"), 3).unwrap();
+    js.prolog();
+    js.reti(42);
+    js.epilog();
+
+    let js = js.emit().unwrap();
+    let text = match js.disassembly() {
+        Ok(text) => text,
+        Err(_) if unsafe { bindings::lgsys_has_disassembler() } == 0 => return,
+        Err(e) => panic!("{}", e),
+    };
+    assert!(text.contains("# answer answer.rs:3\n"));
+}
+
+#[test]
+fn note_iteration() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+
+    js.name(Some("pair")).unwrap();
+    let first = js.note(Some("pair.rs"), 1).unwrap();
+    js.prolog();
+    js.movi(Reg::R(0), 1);
+    let second = js.note(Some("pair.rs"), 2).unwrap();
+    js.addi(Reg::R(0), Reg::R(0), 1);
+    js.retr(Reg::R(0));
+    js.epilog();
+
+    let js = js.emit().unwrap();
+    let lines: Vec<_> = js.notes().map(|(at, info)| (at, info.line)).collect();
+    assert_eq!(lines, vec![(js.address(&first), 1), (js.address(&second), 2)]);
+
+    let info = js.lookup(js.address(&second)).unwrap();
+    assert_eq!(info.name.as_deref(), Some("pair"));
+    assert_eq!(info.file.as_deref(), Some("pair.rs"));
+    assert_eq!(info.line, 2);
+    assert_eq!(info.function_start, js.address(&first));
+
+    assert_eq!(js.lookup(crate::NULL), None);
+}
+
+#[test]
+fn function_ranges() {
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+    let first = js.name(Some("range_first")).unwrap();
+    js.prolog();
+    js.reti(1);
+    js.epilog();
+    let second = js.name(Some("range_second")).unwrap();
+    js.prolog();
+    js.reti(2);
+    js.epilog();
+
+    let js = js.emit().unwrap();
+    let (first, second) = (js.function_range(&first), js.function_range(&second));
+    assert_eq!(first.start, 0);
+    assert_eq!(first.end, second.start);
+    assert_eq!(second.end, js.code_bytes().len());
+    assert!(!js.code_bytes()[first].is_empty());
+}
+
+#[test]
+fn emit_into_buffer() {
+    use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+    struct Region {
+        memory: &'static mut [u8],
+        requests: Vec<usize>,
+    }
+
+    unsafe impl CodeBuffer for Region {
+        fn reserve(&mut self, size: usize) -> Option<&mut [u8]> {
+            self.requests.push(size);
+            self.memory.get_mut(..size)
+        }
+    }
+
+    const LEN: usize = 1 << 16;
+    let memory = unsafe {
+        let prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+        let ptr = libc::mmap(std::ptr::null_mut(), LEN, prot, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+        assert_ne!(ptr, MAP_FAILED);
+        std::slice::from_raw_parts_mut(ptr as *mut u8, LEN)
+    };
+    let (memory, end) = memory.split_at_mut(LEN - 16);
+    let mut region = Region { memory, requests: Vec::new() };
+
+    let mut jit = crate::Jit::new();
+    let mut js = jit.new_state();
+    js.prolog();
+    js.reti(5);
+    js.epilog();
+
+    let js = js.emit_into(&mut region).unwrap();
+    assert_eq!(js.code_addr(), CodeAddr::from(region.memory.as_ptr() as JitPointer));
+    let five = unsafe { js.into_code().cast::<extern "C" fn() -> JitWord>() };
+    assert_eq!(five.call(), 5);
+    assert!(region.requests.windows(2).all(|w| w[0] < w[1]));
+
+    // Offers less than it is asked for.
+    struct Small(&'static mut [u8]);
+    unsafe impl CodeBuffer for Small {
+        fn reserve(&mut self, _: usize) -> Option<&mut [u8]> {
+            Some(&mut *self.0)
+        }
+    }
+    let mut js = jit.new_state();
+    js.prolog();
+    js.reti(6);
+    js.epilog();
+    match js.emit_into(&mut Small(end)).map_err(Error::from) {
+        Err(Error::CodeBufferFull { .. }) => (),
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    }
+
+    drop(five);
+    unsafe { libc::munmap(region.memory.as_mut_ptr() as JitPointer, LEN) };
+}
+
+#[test]
+fn emit_into_retries() {
+    use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+    // Offers exactly what it is asked for, up to a limit.
+    struct Exact {
+        memory: &'static mut [u8],
+        limit: usize,
+        requests: Vec<usize>,
+    }
+
+    unsafe impl CodeBuffer for Exact {
+        fn reserve(&mut self, size: usize) -> Option<&mut [u8]> {
+            self.requests.push(size);
+            if size > self.limit {
+                return None;
+            }
+            self.memory.get_mut(..size)
+        }
+    }
+
+    const LEN: usize = 1 << 16;
+    let memory = unsafe {
+        let prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+        let ptr = libc::mmap(std::ptr::null_mut(), LEN, prot, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+        assert_ne!(ptr, MAP_FAILED);
+        std::slice::from_raw_parts_mut(ptr as *mut u8, LEN)
+    };
+    let mut buffer = Exact { memory, limit: LEN, requests: Vec::new() };
+    fn seven<'j>(jit: &'j mut crate::Jit) -> JitState<'j> {
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(7);
+        js.epilog();
+        js
+    }
+
+    // Lightning's estimate is enough at first, so start below it to make the
+    // first attempt fail.
+    let mut jit = crate::Jit::new();
+    let js = seven(&mut jit).emit_sized(&mut buffer, 16).unwrap();
+    let size = js.code_bytes().len();
+    let seven_code = unsafe { js.into_code().cast::<extern "C" fn() -> JitWord>() };
+    assert_eq!(seven_code.call(), 7);
+    assert_eq!(buffer.requests[0], 16);
+    assert!(buffer.requests.len() > 1, "{:?}", buffer.requests);
+    assert!(buffer.requests.windows(2).all(|w| w[1] == w[0] * 2));
+    assert!(*buffer.requests.last().unwrap() >= size);
+    drop(seven_code);
+
+    // Running out of memory reports the size asked for last.
+    buffer.limit = 32;
+    buffer.requests.clear();
+    match seven(&mut jit).emit_sized(&mut buffer, 16).map_err(Error::from) {
+        Err(Error::CodeBufferFull { size }) => assert_eq!(size, 64),
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    }
+    assert_eq!(buffer.requests, [16, 32, 64]);
+
+    // As does refusing lightning's estimate.
+    buffer.requests.clear();
+    let js = seven(&mut jit);
+    let mut estimate = 0;
+    unsafe {
+        bindings::lgsys_realize(*js.raw());
+        bindings::_jit_get_code(*js.raw(), &mut estimate);
+    }
+    let js = match js.emit_into(&mut buffer) {
+        Err(e) => {
+            assert_eq!(*e.error(), Error::CodeBufferFull { size: estimate as usize });
+            e.into_state().expect("the state is given back")
+        }
+        other => panic!("unexpected {:?}", other.map(|_| ())),
+    };
+
+    // The state given back no longer refers to the buffer, and emits into
+    // memory of lightning's.
+    let seven_code = unsafe { js.emit().unwrap().into_code().cast::<extern "C" fn() -> JitWord>() };
+    assert_eq!(seven_code.call(), 7);
+    let (start, entry) = (buffer.memory.as_ptr() as usize, seven_code.entry_address() as usize);
+    assert!(entry < start || entry >= start + LEN);
+
+    unsafe { libc::munmap(buffer.memory.as_mut_ptr() as JitPointer, LEN) };
+}
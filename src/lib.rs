@@ -9,16 +9,16 @@
 //! let mut js = jit.new_state();
 //!
 //! js.prolog();
-//! let inarg = js.arg();
+//! let inarg = js.arg::<JitWord>();
 //! js.getarg(Reg::R(0), &inarg);
 //! js.addi(Reg::R(0), Reg::R(0), 1);
 //! js.retr(Reg::R(0));
 //!
-//! let incr = unsafe { js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-//! js.clear_state();
+//! let js = unsafe { js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
+//! let incr = js.clear_state();
 //!
-//! assert_eq!(incr(5), 6);
-//! assert_eq!(incr(6), 7);
+//! assert_eq!(incr.call(5), 6);
+//! assert_eq!(incr.call(6), 7);
 //!
 //! ```
 //!
@@ -37,9 +37,9 @@
 //!     // make sure this outlives any calls
 //!     let cs = CString::new("generated %d bytes\n").unwrap();
 //!
-//!     let start = js.note(Some(file!()), line!());
+//!     let start = js.note(Some(file!()), line!()).unwrap();
 //!     js.prolog();
-//!     let inarg = js.arg();
+//!     let inarg = js.arg::<JitWord>();
 //!     js.getarg(Reg::R(1), &inarg);
 //!     js.prepare();
 //!     js.pushargi(cs.as_ptr() as JitWord);
@@ -48,12 +48,13 @@
 //!     js.finishi(libc::printf as JitPointer);
 //!     js.ret();
 //!     js.epilog();
-//!     let end = js.note(Some(file!()), line!());
+//!     let end = js.note(Some(file!()), line!()).unwrap();
 //!
-//!     let my_function = unsafe{ js.cast_emit::<extern fn(JitWord)>() };
+//!     let js = unsafe{ js.cast_emit::<extern fn(JitWord)>() }.unwrap();
+//!     let size = js.address(&end) as u64 - js.address(&start) as u64;
+//!     let my_function = js.clear_state();
 //!     /* call the generated code, passing its size as argument */
-//!     my_function((js.address(&end) as u64 - js.address(&start) as u64).try_into().unwrap());
-//!     js.clear_state();
+//!     my_function.call(size.try_into().unwrap());
 //!
 //!     // TODO: dissasembly has not been implemented yet
 //!     // js.dissasemble();
@@ -71,7 +72,7 @@
 //!
 //!     let label = js.label();
 //!                 js.prolog();
-//!     let inarg = js.arg();
+//!     let inarg = js.arg::<JitWord>();
 //!                 js.getarg(Reg::R(0), &inarg);
 //!     let zero  = js.beqi(Reg::R(0), 0);
 //!                 js.movr(Reg::V(0), Reg::R(0));
@@ -96,14 +97,14 @@
 //!                 js.retr(Reg::R(0));
 //!                 js.epilog();
 //!
-//!     let fib = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-//!     js.clear_state();
+//!     let js = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
+//!     let fib = js.clear_state();
 //!
-//!     println!("fib({})={}", 32, fib(32));
-//!     assert_eq!(0, fib(0));
-//!     assert_eq!(1, fib(1));
-//!     assert_eq!(1, fib(2));
-//!     assert_eq!(2178309, fib(32));
+//!     println!("fib({})={}", 32, fib.call(32));
+//!     assert_eq!(0, fib.call(0));
+//!     assert_eq!(1, fib.call(1));
+//!     assert_eq!(1, fib.call(2));
+//!     assert_eq!(2178309, fib.call(32));
 //! }
 //! ```
 //!
@@ -118,7 +119,7 @@
 //!     let fact = js.forward();
 //!
 //!                 js.prolog();
-//!     let inarg = js.arg();
+//!     let inarg = js.arg::<JitWord>();
 //!                 js.getarg(Reg::R(0), &inarg);
 //!                 js.prepare();
 //!                 js.pushargi(1);
@@ -134,8 +135,8 @@
 //!                 js.prolog();
 //!                 js.frame(16);
 //!     let f_ent = js.label(); // TCO entry point
-//!     let ac    = js.arg();
-//!     let ina   = js.arg();
+//!     let ac    = js.arg::<JitWord>();
+//!     let ina   = js.arg::<JitWord>();
 //!                 js.getarg(Reg::R(0), &ac);
 //!                 js.getarg(Reg::R(1), &ina);
 //!     let f_out = js.blei(Reg::R(1), 1);
@@ -148,15 +149,15 @@
 //!                 js.patch(&f_out);
 //!                 js.retr(Reg::R(0));
 //!
-//!     let factorial = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() };
-//!     js.clear_state();
+//!     let js = unsafe{ js.cast_emit::<extern fn(JitWord) -> JitWord>() }.unwrap();
+//!     let factorial = js.clear_state();
 //!
-//!     println!("factorial({}) = {}", 5, factorial(5));
-//!     assert_eq!(1, factorial(1));
-//!     assert_eq!(2, factorial(2));
-//!     assert_eq!(6, factorial(3));
-//!     assert_eq!(24, factorial(4));
-//!     assert_eq!(120, factorial(5));
+//!     println!("factorial({}) = {}", 5, factorial.call(5));
+//!     assert_eq!(1, factorial.call(1));
+//!     assert_eq!(2, factorial.call(2));
+//!     assert_eq!(6, factorial.call(3));
+//!     assert_eq!(24, factorial.call(4));
+//!     assert_eq!(120, factorial.call(5));
 //! }
 //! ```
 // Enforce some lints for the whole crate.
@@ -183,15 +184,59 @@ extern crate lazy_static;
 #[macro_use]
 pub(crate) mod raw;
 
+pub mod error;
+pub use error::{EmitError, EmitResult, Error, Result};
+
 pub mod jit;
 pub use jit::Jit;
 
 pub mod jitstate;
 pub use jitstate::JitState;
 
+pub mod phase;
+
+pub mod code;
+pub use code::{CodeAddr, CodeBuffer, CompiledCode, JitFn};
+
+pub mod node;
+pub use node::{Label, Forward, Branch, Arg, Note, CallSite};
+
+pub mod notes;
+pub use notes::{NoteInfo, Notes};
+
+#[cfg(target_os = "linux")]
+pub mod perf;
+
+pub mod registry;
+
+mod elf;
+#[cfg(feature = "gdb")]
+mod gdb;
+#[cfg(feature = "unwind")]
+pub(crate) mod unwind;
+
+pub mod function;
+pub use function::{Function, FunctionBuilder};
+
+pub mod data;
+pub use data::{DataBuilder, DataFlags};
+
+pub mod arena;
+pub use arena::{ArenaStats, CodeArena};
+
+pub mod mapping;
+pub use mapping::CodeMapping;
+
+pub mod memory;
+pub use memory::{MemoryQuota, MemoryUsage};
+
+pub mod stats;
+pub use stats::{NodeCategory, Stats};
+
 pub mod types;
 pub use types::NULL;
-pub use types::Reg;
-pub use types::JitNode;
+pub use types::{Reg, FReg, Base};
+pub use types::{JitNode, StateId};
 pub use types::{JitWord, JitUword, JitPointer};
+pub use types::JitFnPtr;
 pub(crate) use types::ToFFI;
@@ -0,0 +1,204 @@
+//! Emission that never maps code writable and executable at once.
+//!
+//! While it emits, lightning maps the memory for code readable, writable and
+//! executable, which hardened kernels and policies such as SELinux's
+//! `execmem` refuse. `JitState::emit_mapped` instead writes the code into
+//! memory that is only readable and writable, then makes it readable and
+//! executable. Later changes go through `CompiledCode::patch`, which makes
+//! the code writable again for as long as they take.
+
+use crate::bindings;
+use crate::code::{protect, CodeBuffer, Protection};
+use crate::{EmitError, EmitResult, JitPointer, JitState, JitWord};
+
+/// How `JitState::emit_mapped` maps the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CodeMapping {
+    /// Writes the code into readable and writable memory, then makes it
+    /// readable and executable.
+    WriteXorExecute,
+    /// As `WriteXorExecute`, but also maps the memory a second time, writable,
+    /// so that `CompiledCode::patch` can change the code while it stays
+    /// executable. This needs memory files, which only Linux provides.
+    DualMapped,
+}
+
+/// The mappings made for a single emission, which releases the one it made
+/// before when it is asked for more.
+struct Mapper {
+    mapping: CodeMapping,
+    current: Option<Mapped>,
+}
+
+#[derive(Debug)]
+struct Mapped {
+    ptr: JitPointer,
+    len: usize,
+    alias: Option<JitPointer>,
+}
+
+impl<'a> JitState<'a> {
+    /// Emits the machine code for this state, as `emit` does, into memory
+    /// mapped as `mapping` says.
+    ///
+    /// Fails if the memory cannot be mapped, or made executable; the error
+    /// gives the state back in the first case.
+    pub fn emit_mapped(self, mapping: CodeMapping) -> EmitResult<'a> {
+        let mut mapper = Mapper { mapping, current: None };
+        let mut js = self.emit_into(&mut mapper)?;
+
+        // From here on, the code owns the mappings.
+        let mapped = mapper.current.take().expect("emitted code has a mapping");
+        let protection = match mapped.alias {
+            Some(alias) => Protection::Alias(alias as usize),
+            None => Protection::Flip,
+        };
+        js.phase.code.own_mapping(protection);
+        protect(mapped.ptr, mapped.len as JitWord, true).map_err(EmitError::after)?;
+        Ok(js)
+    }
+}
+
+impl Mapper {
+    fn release(&mut self) {
+        if let Some(mapped) = self.current.take() {
+            let len = mapped.len as JitWord;
+            unsafe {
+                bindings::lgsys_unmap(mapped.ptr, len);
+                if let Some(alias) = mapped.alias {
+                    bindings::lgsys_unmap(alias, len);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Mapper {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+// The mappings are only writable while lightning emits into them;
+// `emit_mapped` makes the one it keeps executable before returning the code.
+unsafe impl CodeBuffer for Mapper {
+    fn reserve(&mut self, size: usize) -> Option<&mut [u8]> {
+        self.release();
+        let len = round_up(size.max(1), page_size());
+        let (ptr, alias) = match self.mapping {
+            CodeMapping::WriteXorExecute => unsafe { (bindings::lgsys_map_writable(len as JitWord), None) },
+            CodeMapping::DualMapped => {
+                let mut alias = std::ptr::null_mut();
+                let ptr = unsafe { bindings::lgsys_map_dual(len as JitWord, &mut alias) };
+                (ptr, Some(alias))
+            }
+        };
+        if ptr.is_null() {
+            return None;
+        }
+        self.current = Some(Mapped { ptr, len, alias });
+        Some(unsafe { std::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    unsafe { bindings::lgsys_page_size() as usize }
+}
+
+/// Rounds `value` up to a multiple of `to`, which must be a power of two.
+pub(crate) fn round_up(value: usize, to: usize) -> usize {
+    (value + to - 1) & !(to - 1)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Jit, JitWord};
+
+    /// Returns the permissions of the mapping that contains `addr`, as listed
+    /// in `/proc/self/maps`.
+    pub(crate) fn permissions(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap();
+            let mut bounds = range.split('-').map(|b| usize::from_str_radix(b, 16).unwrap());
+            let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+            if (start..end).contains(&addr) {
+                return fields.next().unwrap()[..3].to_string();
+            }
+        }
+        panic!("{:#x} is not mapped", addr);
+    }
+
+    fn emit_answer(jit: &mut Jit, mapping: CodeMapping) -> crate::CompiledCode<extern "C" fn() -> JitWord> {
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(42);
+        js.epilog();
+        unsafe { js.emit_mapped(mapping).unwrap().into_code().cast() }
+    }
+
+    #[test]
+    fn write_xor_execute() {
+        let mut jit = Jit::new();
+        let mut answer = emit_answer(&mut jit, CodeMapping::WriteXorExecute);
+        let at = answer.entry_address() as usize;
+        assert_eq!(permissions(at), "r-x");
+        assert_eq!(answer.call(), 42);
+
+        let before = answer.code_bytes().to_vec();
+        let writable = answer.patch(|code| {
+            code.copy_from_slice(&before);
+            permissions(at)
+        });
+        assert_eq!(writable.unwrap(), "rw-");
+        assert_eq!(permissions(at), "r-x");
+        assert_eq!(answer.call(), 42);
+    }
+
+    #[test]
+    fn patch_panics() {
+        let mut jit = Jit::new();
+        let mut answer = emit_answer(&mut jit, CodeMapping::WriteXorExecute);
+        let at = answer.entry_address() as usize;
+
+        let patched = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            answer.patch(|_| panic!("patch failed")).unwrap();
+        }));
+        assert!(patched.is_err());
+        assert_eq!(permissions(at), "r-x");
+        assert_eq!(answer.call(), 42);
+    }
+
+    #[test]
+    fn dual_mapped() {
+        let mut jit = Jit::new();
+        let mut answer = emit_answer(&mut jit, CodeMapping::DualMapped);
+        let at = answer.entry_address() as usize;
+        assert_eq!(permissions(at), "r-x");
+
+        let alias = answer.patch(|code| code.as_ptr() as usize).unwrap();
+        assert_ne!(alias, at);
+        assert_eq!(permissions(alias), "rw-");
+        assert_eq!(permissions(at), "r-x");
+        assert_eq!(answer.call(), 42);
+    }
+
+    #[test]
+    fn patch_mapped_by_lightning() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(7);
+        js.epilog();
+        let mut seven = unsafe { js.emit().unwrap().into_code().cast::<extern "C" fn() -> JitWord>() };
+
+        let before = seven.code_bytes().to_vec();
+        let len = seven.patch(|code| code.len()).unwrap();
+        assert_eq!(len, before.len());
+        assert_eq!(seven.code_bytes(), &before[..]);
+        assert_eq!(seven.call(), 7);
+    }
+}
@@ -0,0 +1,514 @@
+//! Accounting of the memory used by states and the code they emit.
+//!
+//! Each `Jit` counts the executable memory, constants and notes held by the
+//! live `CompiledCode` emitted from its states, the memory lightning holds
+//! for the nodes of its live states, and the number of those states; see
+//! `Jit::usage`. A `MemoryQuota` set with `Jit::set_quota` makes emitting
+//! fail with `Error::QuotaExceeded` instead of going past it.
+//!
+//! lightning allocates through memory hooks that are shared by the whole
+//! process, and that are not told which state they allocate for. A
+//! `JitState` therefore names its `Jit` to the hooks of the current thread
+//! while it calls into lightning, and each allocation remembers the `Jit` it
+//! was counted against until it is freed. Allocations made outside any state
+//! are only counted for the whole process; see `state_bytes`. The hooks take
+//! the memory from the global allocator, or from the allocator given to
+//! `Jit::set_allocator`.
+
+use crate::bindings;
+use crate::{Error, JitState, Result};
+
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+
+/// The memory used by the states and code of a `Jit`, as returned by
+/// `Jit::usage`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The number of bytes of executable memory held by live code: whole
+    /// pages for code that lightning mapped, and the memory taken from a
+    /// `CodeBuffer` or a `CodeArena` otherwise.
+    pub code: usize,
+    /// The number of bytes of constants in live code.
+    pub data: usize,
+    /// The number of bytes of notes in live code.
+    pub notes: usize,
+    /// The number of bytes that lightning holds for the nodes and
+    /// bookkeeping of live states.
+    pub ir: usize,
+    /// The number of live states.
+    pub states: usize,
+}
+
+/// Limits on the memory used by the states and code of a `Jit`; `None` means
+/// no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryQuota {
+    /// The most bytes of executable memory that live code may hold.
+    pub code: Option<usize>,
+    /// The most bytes of constants that live code may hold.
+    pub data: Option<usize>,
+    /// The most bytes of notes that live code may hold.
+    pub notes: Option<usize>,
+    /// The most bytes that lightning may hold for live states. Building a
+    /// state cannot fail, so a state that goes past this fails to emit.
+    pub ir: Option<usize>,
+}
+
+/// The kinds of memory that a `MemoryQuota` limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Resource {
+    Code,
+    Data,
+    Notes,
+    Ir,
+}
+
+/// The counters and quota of a `Jit`, shared with its states and their code.
+#[derive(Debug, Default)]
+pub(crate) struct Accounting {
+    code: AtomicUsize,
+    data: AtomicUsize,
+    notes: AtomicUsize,
+    ir: AtomicUsize,
+    states: AtomicUsize,
+    quota: Mutex<MemoryQuota>,
+}
+
+/// The memory of a `CompiledCode`, counted against its `Jit` until this is
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    accounting: Arc<Accounting>,
+    code: usize,
+    data: usize,
+    notes: usize,
+}
+
+impl Accounting {
+    pub(crate) fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            code: self.code.load(Ordering::Relaxed),
+            data: self.data.load(Ordering::Relaxed),
+            notes: self.notes.load(Ordering::Relaxed),
+            ir: self.ir.load(Ordering::Relaxed),
+            states: self.states.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn quota(&self) -> MemoryQuota {
+        *self.quota.lock().unwrap()
+    }
+
+    pub(crate) fn set_quota(&self, quota: MemoryQuota) {
+        *self.quota.lock().unwrap() = quota;
+    }
+
+    /// Fails if code of the given sizes would take the usage past the quota,
+    /// or if the states already hold more than it allows.
+    pub(crate) fn check(&self, code: usize, data: usize, notes: usize) -> Result<()> {
+        let quota = self.quota();
+        if let Some(limit) = quota.ir {
+            let used = self.ir.load(Ordering::Relaxed);
+            if used > limit {
+                return Err(Error::QuotaExceeded { resource: Resource::Ir, limit, requested: used });
+            }
+        }
+        for &(resource, limit, used, requested) in &[
+            (Resource::Code, quota.code, &self.code, code),
+            (Resource::Data, quota.data, &self.data, data),
+            (Resource::Notes, quota.notes, &self.notes, notes),
+        ] {
+            if let Some(limit) = limit {
+                if used.load(Ordering::Relaxed).saturating_add(requested) > limit {
+                    return Err(Error::QuotaExceeded { resource, limit, requested });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn add_state(&self) {
+        self.states.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove_state(&self) {
+        self.states.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Charge {
+    /// Counts code of the given sizes against `accounting`.
+    pub(crate) fn new(accounting: &Arc<Accounting>, code: usize, data: usize, notes: usize) -> Charge {
+        accounting.code.fetch_add(code, Ordering::Relaxed);
+        accounting.data.fetch_add(data, Ordering::Relaxed);
+        accounting.notes.fetch_add(notes, Ordering::Relaxed);
+        Charge { accounting: Arc::clone(accounting), code, data, notes }
+    }
+
+    /// Refunds the code memory past the first `code` bytes.
+    pub(crate) fn shrink_code(&mut self, code: usize) {
+        debug_assert!(code <= self.code);
+        self.accounting.code.fetch_sub(self.code - code, Ordering::Relaxed);
+        self.code = code;
+    }
+
+    /// Moves `code` bytes of the code memory to a charge of their own.
+    pub(crate) fn split_code(&mut self, code: usize) -> Charge {
+        debug_assert!(code <= self.code);
+        self.code -= code;
+        Charge { accounting: Arc::clone(&self.accounting), code, data: 0, notes: 0 }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.accounting.code.fetch_sub(self.code, Ordering::Relaxed);
+        self.accounting.data.fetch_sub(self.data, Ordering::Relaxed);
+        self.accounting.notes.fetch_sub(self.notes, Ordering::Relaxed);
+    }
+}
+
+impl<'a, P> JitState<'a, P> {
+    /// Returns the memory used by the `Jit` that made this state, which
+    /// `Jit::usage` cannot be asked for while the state lives.
+    pub fn jit_usage(&self) -> MemoryUsage {
+        self.accounting.usage()
+    }
+}
+
+/// The bytes that lightning has allocated through its memory hooks, and the
+/// number of allocations they make up.
+static STATE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static STATE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The allocator given to `Jit::set_allocator`, or null for the global one.
+static ALLOCATOR: AtomicPtr<Allocator> = AtomicPtr::new(ptr::null_mut());
+
+/// The size of the `Header` in front of each allocation, which also keeps
+/// the memory aligned for any type.
+const HEADER: usize = 16;
+
+/// What the hooks record in front of each allocation.
+#[repr(C)]
+struct Header {
+    size: usize,
+    // The accounting the memory is counted against, from `Arc::into_raw`,
+    // or null.
+    owner: *const Accounting,
+}
+
+thread_local! {
+    // The accounting of the `Jit` whose state is calling into lightning on
+    // this thread, or null. A `const` initializer needs a newer Rust than we
+    // support.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static OWNER: Cell<*const Accounting> = Cell::new(ptr::null());
+}
+
+/// A lightning state, with what lightning allocates on this thread counted
+/// against a `Jit` until this is dropped; returned by `JitState::raw`.
+pub(crate) struct Attributed {
+    state: *mut bindings::jit_state_t,
+    previous: *const Accounting,
+}
+
+/// Counts what lightning allocates on this thread for `state` against
+/// `accounting`, until the result is dropped.
+pub(crate) fn attribute(accounting: &Arc<Accounting>, state: *mut bindings::jit_state_t) -> Attributed {
+    let owner: *const Accounting = &**accounting;
+    let previous = OWNER.try_with(|o| o.replace(owner)).unwrap_or(ptr::null());
+    Attributed { state, previous }
+}
+
+impl Deref for Attributed {
+    type Target = *mut bindings::jit_state_t;
+
+    fn deref(&self) -> &*mut bindings::jit_state_t {
+        &self.state
+    }
+}
+
+impl Drop for Attributed {
+    fn drop(&mut self) {
+        let _ = OWNER.try_with(|o| o.set(self.previous));
+    }
+}
+
+/// Returns a new reference to the accounting that allocations on this
+/// thread are counted against, or null.
+fn owner() -> *const Accounting {
+    let owner = OWNER.try_with(Cell::get).unwrap_or(ptr::null());
+    if owner.is_null() {
+        return owner;
+    }
+    // `Attributed` keeps a reference alive, so another can be taken.
+    let accounting = ManuallyDrop::new(unsafe { Arc::from_raw(owner) });
+    Arc::into_raw(Arc::clone(&accounting))
+}
+
+/// Counts `size` bytes for the process and, unless null, for `owner`.
+fn charge(owner: *const Accounting, size: usize) {
+    STATE_BYTES.fetch_add(size, Ordering::Relaxed);
+    if let Some(owner) = unsafe { owner.as_ref() } {
+        owner.ir.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// Stops counting `size` bytes for the process and `owner`.
+fn refund(owner: *const Accounting, size: usize) {
+    STATE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    if let Some(owner) = unsafe { owner.as_ref() } {
+        owner.ir.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+struct Allocator(&'static (dyn GlobalAlloc + Sync));
+
+/// The global allocator, as a `GlobalAlloc`.
+struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        alloc::realloc(ptr, layout, new_size)
+    }
+}
+
+/// Returns the number of bytes that lightning holds in the whole process,
+/// which is mostly the nodes and bookkeeping of live states; `Jit::usage`
+/// gives the part held for the states of one `Jit`.
+pub fn state_bytes() -> usize {
+    STATE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Points lightning's memory hooks at the counting allocator. This must
+/// happen before lightning allocates anything, since memory is freed by the
+/// hook that allocated it.
+pub(crate) fn install_hooks() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        bindings::jit_set_memory_funcs(Some(hook_alloc), Some(hook_realloc), Some(hook_free));
+    });
+}
+
+/// Makes the hooks take memory from `allocator`. The caller must make sure
+/// that lightning is not running, and holds no memory from the previous
+/// allocator; fails otherwise.
+pub(crate) fn set_allocator(allocator: &'static (dyn GlobalAlloc + Sync)) -> Result<()> {
+    if STATE_ALLOCATIONS.load(Ordering::Acquire) != 0 {
+        return Err(Error::AllocatorInUse);
+    }
+    let allocator = Box::into_raw(Box::new(Allocator(allocator)));
+    let previous = ALLOCATOR.swap(allocator, Ordering::AcqRel);
+    if !previous.is_null() {
+        // Nothing runs the hooks while lightning is not running.
+        drop(unsafe { Box::from_raw(previous) });
+    }
+    Ok(())
+}
+
+fn allocator() -> &'static dyn GlobalAlloc {
+    match unsafe { ALLOCATOR.load(Ordering::Acquire).as_ref() } {
+        Some(allocator) => allocator.0,
+        None => &Global,
+    }
+}
+
+fn layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(HEADER)?, HEADER).ok()
+}
+
+unsafe extern "C" fn hook_alloc(size: bindings::size_t) -> *mut c_void {
+    let size = size as usize;
+    let layout = match layout(size) {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+    let base = allocator().alloc(layout);
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    let owner = owner();
+    (base as *mut Header).write(Header { size, owner });
+    charge(owner, size);
+    STATE_ALLOCATIONS.fetch_add(1, Ordering::Release);
+    base.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn hook_realloc(ptr: *mut c_void, size: bindings::size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return hook_alloc(size);
+    }
+    let size = size as usize;
+    if layout(size).is_none() {
+        return ptr::null_mut();
+    }
+    let base = (ptr as *mut u8).sub(HEADER);
+    let Header { size: old, owner } = (base as *const Header).read();
+    let old_layout = Layout::from_size_align_unchecked(old + HEADER, HEADER);
+    let moved = allocator().realloc(base, old_layout, size + HEADER);
+    if moved.is_null() {
+        return ptr::null_mut();
+    }
+    // The memory stays with the `Jit` it was first counted against.
+    (moved as *mut Header).write(Header { size, owner });
+    refund(owner, old);
+    charge(owner, size);
+    moved.add(HEADER) as *mut c_void
+}
+
+unsafe extern "C" fn hook_free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let base = (ptr as *mut u8).sub(HEADER);
+    let Header { size, owner } = (base as *const Header).read();
+    refund(owner, size);
+    STATE_ALLOCATIONS.fetch_sub(1, Ordering::Release);
+    allocator().dealloc(base, Layout::from_size_align_unchecked(size + HEADER, HEADER));
+    if !owner.is_null() {
+        drop(Arc::from_raw(owner));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Jit, Reg};
+
+    fn answer<'j>(jit: &'j mut Jit) -> JitState<'j> {
+        let mut js = jit.new_state();
+        js.prolog();
+        js.movi_d(crate::FReg::F(0), 2.5);
+        js.movi(Reg::R(0), 42);
+        js.retr(Reg::R(0));
+        js.epilog();
+        js
+    }
+
+    #[test]
+    fn counts_live_code() {
+        let mut jit = Jit::new();
+        assert_eq!(jit.usage(), MemoryUsage::default());
+
+        let js = answer(&mut jit);
+        assert!(state_bytes() > 0);
+        let code = js.emit().unwrap().into_code();
+        let usage = jit.usage();
+        assert_eq!(usage.code, code.code_size());
+        assert!(usage.code >= code.code_bytes().len());
+        assert_eq!(usage.data, code.data_bytes().len());
+        assert_eq!(usage.states, 0);
+
+        drop(code);
+        assert_eq!(jit.usage(), MemoryUsage::default());
+    }
+
+    #[test]
+    fn counts_states() {
+        let mut jit = Jit::new();
+        let js = answer(&mut jit);
+        assert_eq!(js.jit_usage().states, 1);
+        let js = js.emit().unwrap();
+        assert_eq!(js.jit_usage().states, 1);
+        let _code = js.into_code();
+        assert_eq!(jit.usage().states, 0);
+    }
+
+    #[test]
+    fn quota() {
+        let mut jit = Jit::new();
+        jit.set_quota(MemoryQuota { code: Some(1), ..MemoryQuota::default() });
+        match answer(&mut jit).emit().map_err(Error::from) {
+            Err(Error::QuotaExceeded { resource: Resource::Code, limit: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(jit.usage(), MemoryUsage::default());
+
+        jit.set_quota(MemoryQuota { code: Some(1 << 20), ..MemoryQuota::default() });
+        let code = answer(&mut jit).emit().unwrap().into_code();
+        let used = jit.usage().code;
+        assert!(used > 0);
+        jit.set_quota(MemoryQuota { code: Some(used), ..MemoryQuota::default() });
+        let js = match answer(&mut jit).emit() {
+            Err(e) => e.into_state().expect("the state is given back"),
+            Ok(_) => panic!("the quota was exceeded"),
+        };
+        assert_eq!(js.jit_usage().code, used);
+
+        // Dropping the other code makes room for the state given back.
+        drop(code);
+        let _code = js.emit().unwrap().into_code();
+        assert_eq!(jit.usage().code, used);
+    }
+
+    #[test]
+    fn counts_ir() {
+        assert!(std::mem::size_of::<Header>() <= HEADER);
+        let mut jit = Jit::new();
+        let mut other = Jit::new();
+        let js = answer(&mut jit);
+        let used = js.jit_usage().ir;
+        assert!(used > 0);
+
+        // The states of another `Jit` are counted against it alone.
+        let mut big = other.new_state();
+        for _ in 0..1000 {
+            big.addi(Reg::R(0), Reg::R(0), 1);
+        }
+        assert!(big.jit_usage().ir > used);
+        assert_eq!(js.jit_usage().ir, used);
+
+        drop(big);
+        drop(js);
+        assert_eq!(jit.usage().ir, 0);
+        assert_eq!(other.usage().ir, 0);
+    }
+
+    #[test]
+    fn ir_quota() {
+        let mut jit = Jit::new();
+        let small = answer(&mut jit).jit_usage().ir;
+        jit.set_quota(MemoryQuota { ir: Some(small * 4), ..MemoryQuota::default() });
+        answer(&mut jit).emit().unwrap();
+
+        let mut js = jit.new_state();
+        js.prolog();
+        for _ in 0..1000 {
+            js.addi(Reg::R(0), Reg::R(0), 1);
+        }
+        js.retr(Reg::R(0));
+        js.epilog();
+        match js.emit().map_err(Error::from) {
+            Err(Error::QuotaExceeded { resource: Resource::Ir, limit, requested }) => {
+                assert_eq!(limit, small * 4);
+                assert!(requested > limit);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(jit.usage().ir, 0);
+    }
+
+    #[test]
+    fn allocator_in_use() {
+        static SYSTEM: std::alloc::System = std::alloc::System;
+        let mut jit = Jit::new();
+        let _js = answer(&mut jit);
+        assert_eq!(Jit::set_allocator(&SYSTEM), Err(Error::AllocatorInUse));
+    }
+}
@@ -0,0 +1,128 @@
+//! Typed handles for the nodes that lightning returns.
+//!
+//! Every instruction is represented by a node, but only some nodes are
+//! meaningful as operands of other instructions: a branch can be patched to
+//! jump to a label, an argument can be read with `getarg`, and so on. The
+//! handles in this module record what kind of node lightning returned, so
+//! that such operands are checked at compile time.
+
+use crate::bindings;
+use crate::types::StateId;
+use crate::JitNode;
+use crate::ToFFI;
+
+use std::marker::PhantomData;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Any typed node handle.
+///
+/// This trait is sealed; it cannot be implemented outside this crate.
+pub trait Node<'a>: private::Sealed {
+    /// Returns the underlying untyped node.
+    fn node(&self) -> &JitNode<'a>;
+}
+
+/// Nodes that can be patched to refer to a target: branches, jumps and calls.
+pub trait Patchable<'a>: Node<'a> {}
+
+/// Nodes that can be the target of a patched branch, jump or call.
+pub trait Target<'a>: Node<'a> {}
+
+/// Nodes whose address can be queried after `emit`.
+pub trait Addressable<'a>: Node<'a> {}
+
+macro_rules! node_kind {
+    ( $( #[$attr:meta] )* $kind:ident ) => {
+        $( #[$attr] )*
+        #[derive(Clone, Debug)]
+        pub struct $kind<'a>(pub(crate) JitNode<'a>);
+
+        impl<'a> private::Sealed for $kind<'a> {}
+
+        impl<'a> Node<'a> for $kind<'a> {
+            fn node(&self) -> &JitNode<'a> {
+                &self.0
+            }
+        }
+
+        impl<'a> ToFFI for $kind<'a> {
+            type Type = *mut bindings::jit_node_t;
+
+            fn to_ffi(&self) -> Self::Type {
+                self.0.to_ffi()
+            }
+
+            fn check_state(&self, state: StateId) {
+                self.0.check_state(state)
+            }
+        }
+    };
+}
+
+node_kind! {
+    /// A position in the code, returned by `label` and `indirect`.
+    Label
+}
+
+node_kind! {
+    /// A label that is placed later with `link`, returned by `forward`.
+    Forward
+}
+
+node_kind! {
+    /// A branch or jump, returned by `jmpi` and the `b*` instructions.
+    Branch
+}
+
+node_kind! {
+    /// An annotation, returned by `note` and `name`.
+    Note
+}
+
+node_kind! {
+    /// A call to an immediate address, returned by `finishi` and `calli`.
+    CallSite
+}
+
+/// A function argument of type `T`, returned by `arg`, `arg_f` and `arg_d`.
+#[derive(Clone, Debug)]
+pub struct Arg<'a, T>(pub(crate) JitNode<'a>, PhantomData<T>);
+
+impl<'a, T> Arg<'a, T> {
+    pub(crate) fn new(node: JitNode<'a>) -> Self {
+        Arg(node, PhantomData)
+    }
+}
+
+impl<'a, T> private::Sealed for Arg<'a, T> {}
+
+impl<'a, T> Node<'a> for Arg<'a, T> {
+    fn node(&self) -> &JitNode<'a> {
+        &self.0
+    }
+}
+
+impl<'a, T> ToFFI for Arg<'a, T> {
+    type Type = *mut bindings::jit_node_t;
+
+    fn to_ffi(&self) -> Self::Type {
+        self.0.to_ffi()
+    }
+
+    fn check_state(&self, state: StateId) {
+        self.0.check_state(state)
+    }
+}
+
+impl<'a> Patchable<'a> for Branch<'a> {}
+impl<'a> Patchable<'a> for CallSite<'a> {}
+
+impl<'a> Target<'a> for Label<'a> {}
+impl<'a> Target<'a> for Forward<'a> {}
+
+impl<'a> Addressable<'a> for Label<'a> {}
+impl<'a> Addressable<'a> for Forward<'a> {}
+impl<'a> Addressable<'a> for Note<'a> {}
@@ -0,0 +1,137 @@
+//! Source locations recorded with `JitState::name` and `JitState::note`.
+
+use crate::bindings;
+use crate::JitPointer;
+
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+
+/// The source location of a piece of emitted code.
+///
+/// The strings borrow from the data buffer of the emitted code, and are only
+/// copied if they are not valid UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoteInfo<'s> {
+    /// The name given to the function by `JitState::name`.
+    pub name: Option<Cow<'s, str>>,
+    /// The file given by `JitState::note`.
+    pub file: Option<Cow<'s, str>>,
+    /// The line given by `JitState::note`.
+    pub line: i32,
+    /// The address of the first instruction of the function.
+    pub function_start: JitPointer,
+}
+
+/// The code covered by a note, from a `JitState::name` to the next one.
+#[derive(Debug)]
+pub(crate) struct Function<'s> {
+    pub(crate) name: Option<Cow<'s, str>>,
+    pub(crate) start: usize,
+    pub(crate) size: usize,
+}
+
+/// An iterator over the notes of an emitted state, returned by
+/// `JitState::notes`.
+///
+/// Each item is the address of the first instruction of a line along with its
+/// `NoteInfo`, in the order in which the notes were recorded.
+#[derive(Debug)]
+pub struct Notes<'s> {
+    state: *mut bindings::jit_state_t,
+    cursor: bindings::lgsys_note_cursor,
+    phantom: PhantomData<&'s ()>,
+}
+
+impl<'s> NoteInfo<'s> {
+    /// # Safety
+    /// The strings of `entry` must live for `'s`.
+    unsafe fn from_entry(entry: &bindings::lgsys_note) -> NoteInfo<'s> {
+        unsafe fn text<'s>(s: *const c_char) -> Option<Cow<'s, str>> {
+            if s.is_null() { None } else { Some(CStr::from_ptr(s).to_string_lossy()) }
+        }
+
+        NoteInfo {
+            name: text(entry.name),
+            file: text(entry.file),
+            line: entry.line,
+            function_start: entry.code as JitPointer,
+        }
+    }
+}
+
+impl<'s> Notes<'s> {
+    /// # Safety
+    /// `state` must have been emitted and must not be cleared during `'s`.
+    pub(crate) unsafe fn new(state: *mut bindings::jit_state_t) -> Notes<'s> {
+        Notes {
+            state,
+            cursor: bindings::lgsys_note_cursor { note: 0, line: 0, offset: 0 },
+            phantom: PhantomData,
+        }
+    }
+
+    /// Finds the last line that starts at or before `addr` within the code of
+    /// the note containing `addr`.
+    pub(crate) fn lookup(mut self, addr: JitPointer) -> Option<NoteInfo<'s>> {
+        let addr = addr as usize;
+        std::iter::from_fn(|| self.next_entry())
+            .filter(|entry| {
+                let start = entry.code as usize;
+                addr >= start && addr < start + entry.size as usize
+                    && addr >= entry.address as usize
+            })
+            .max_by_key(|entry| entry.address as usize)
+            .map(|entry| unsafe { NoteInfo::from_entry(&entry) })
+    }
+
+    /// Returns the code covered by each note, ordered by address. Each one
+    /// ends where the next begins, and the last one where lightning says.
+    pub(crate) fn functions(&self) -> Vec<Function<'s>> {
+        let mut entries = Vec::new();
+        for index in 0.. {
+            let mut entry = std::mem::MaybeUninit::uninit();
+            if unsafe { bindings::lgsys_note_function(self.state, index, entry.as_mut_ptr()) } == 0 {
+                break;
+            }
+            let entry = unsafe { entry.assume_init() };
+            if !entry.code.is_null() {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by_key(|entry| entry.code as usize);
+
+        let mut functions = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            let start = entry.code as usize;
+            let size = match entries.get(i + 1) {
+                Some(next) => next.code as usize - start,
+                None => entry.size as usize,
+            };
+            let name = unsafe { NoteInfo::from_entry(entry) }.name;
+            functions.push(Function { name, start, size });
+        }
+        functions
+    }
+
+    fn next_entry(&mut self) -> Option<bindings::lgsys_note> {
+        let mut entry = std::mem::MaybeUninit::uninit();
+        unsafe {
+            if bindings::lgsys_next_note(self.state, &mut self.cursor, entry.as_mut_ptr()) != 0 {
+                Some(entry.assume_init())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl<'s> Iterator for Notes<'s> {
+    type Item = (JitPointer, NoteInfo<'s>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+            .map(|entry| (entry.address as JitPointer, unsafe { NoteInfo::from_entry(&entry) }))
+    }
+}
@@ -0,0 +1,237 @@
+//! Output that lets the Linux `perf` profiler name emitted functions.
+//!
+//! A `PerfMap` records the functions of emitted states that were named with
+//! `JitState::name`. By default it appends them to `/tmp/perf-<pid>.map`,
+//! which `perf report` reads on its own. As a jitdump, it instead writes the
+//! code of each function along with the lines recorded by `JitState::note`;
+//! `perf record -k mono` picks the file up, and `perf inject --jit` merges it
+//! into the recording.
+
+use crate::bindings;
+use crate::elf;
+use crate::phase::Emitted;
+use crate::JitState;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+const JIT_CODE_CLOSE: u32 = 3;
+
+/// A sink for the names of emitted functions; see the module documentation.
+#[derive(Debug)]
+pub struct PerfMap {
+    file: File,
+    jitdump: Option<JitDump>,
+}
+
+#[derive(Debug)]
+struct JitDump {
+    mark: usize,
+    code_index: u64,
+}
+
+impl PerfMap {
+    /// Opens `/tmp/perf-<pid>.map` for appending.
+    pub fn new() -> io::Result<PerfMap> {
+        let path = format!("/tmp/perf-{}.map", process::id());
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(PerfMap { file, jitdump: None })
+    }
+
+    /// Creates the jitdump `jit-<pid>.dump` in `dir`.
+    pub fn jitdump(dir: impl AsRef<Path>) -> io::Result<PerfMap> {
+        let path = dir.as_ref().join(format!("jit-{}.dump", process::id()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+        header.extend_from_slice(&JITDUMP_MAGIC.to_ne_bytes());
+        header.extend_from_slice(&JITDUMP_VERSION.to_ne_bytes());
+        header.extend_from_slice(&JITDUMP_HEADER_SIZE.to_ne_bytes());
+        header.extend_from_slice(&u32::from(elf::MACHINE).to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&process::id().to_ne_bytes());
+        header.extend_from_slice(&timestamp().to_ne_bytes());
+        header.extend_from_slice(&0u64.to_ne_bytes());
+        file.write_all(&header)?;
+
+        let mark = unsafe { bindings::lgsys_perf_mark(file.as_raw_fd()) };
+        if mark.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let jitdump = JitDump { mark: mark as usize, code_index: 0 };
+        Ok(PerfMap { file, jitdump: Some(jitdump) })
+    }
+
+    /// Records the functions of `js` that were named with `JitState::name`.
+    /// Each one extends to the address of the next name.
+    pub fn record<F>(&mut self, js: &JitState<'_, Emitted<F>>) -> io::Result<()> {
+        for function in js.notes().functions() {
+            let name = match &function.name {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let jitdump = match &mut self.jitdump {
+                Some(jitdump) => jitdump,
+                None => {
+                    let line = format!("{:x} {:x} {}\n", function.start, function.size, name);
+                    self.file.write_all(line.as_bytes())?;
+                    continue;
+                }
+            };
+
+            let end = function.start + function.size;
+            let lines: Vec<_> = js
+                .notes()
+                .filter(|(at, _)| (function.start..end).contains(&(*at as usize)))
+                .collect();
+            if !lines.is_empty() {
+                let mut body = Vec::new();
+                body.extend_from_slice(&(function.start as u64).to_ne_bytes());
+                body.extend_from_slice(&(lines.len() as u64).to_ne_bytes());
+                for (at, info) in &lines {
+                    body.extend_from_slice(&(*at as u64).to_ne_bytes());
+                    body.extend_from_slice(&info.line.to_ne_bytes());
+                    body.extend_from_slice(&0i32.to_ne_bytes());
+                    body.extend_from_slice(info.file.as_ref().map_or("", |f| &**f).as_bytes());
+                    body.push(0);
+                }
+                write_record(&mut self.file, JIT_CODE_DEBUG_INFO, &body)?;
+            }
+
+            let code = unsafe { std::slice::from_raw_parts(function.start as *const u8, function.size) };
+            let mut body = Vec::new();
+            body.extend_from_slice(&process::id().to_ne_bytes());
+            body.extend_from_slice(&(unsafe { bindings::lgsys_perf_tid() } as u32).to_ne_bytes());
+            body.extend_from_slice(&(function.start as u64).to_ne_bytes());
+            body.extend_from_slice(&(function.start as u64).to_ne_bytes());
+            body.extend_from_slice(&(function.size as u64).to_ne_bytes());
+            body.extend_from_slice(&jitdump.code_index.to_ne_bytes());
+            body.extend_from_slice(name.as_bytes());
+            body.push(0);
+            body.extend_from_slice(code);
+            write_record(&mut self.file, JIT_CODE_LOAD, &body)?;
+            jitdump.code_index += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PerfMap {
+    fn drop(&mut self) {
+        if let Some(jitdump) = &self.jitdump {
+            let _ = write_record(&mut self.file, JIT_CODE_CLOSE, &[]);
+            unsafe { bindings::lgsys_perf_unmark(jitdump.mark as bindings::jit_pointer_t) };
+        }
+    }
+}
+
+fn timestamp() -> u64 {
+    unsafe { bindings::lgsys_perf_timestamp() as u64 }
+}
+
+/// Writes a jitdump record, which starts with its kind, size and time.
+fn write_record(file: &mut File, id: u32, body: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(16 + body.len());
+    record.extend_from_slice(&id.to_ne_bytes());
+    record.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+    record.extend_from_slice(&timestamp().to_ne_bytes());
+    record.extend_from_slice(body);
+    file.write_all(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Jit, Reg};
+    use super::*;
+
+    use std::convert::TryInto;
+    use std::path::PathBuf;
+
+    /// Removes the file at its path when dropped, even if the test fails.
+    struct Remove(PathBuf);
+
+    impl Drop for Remove {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn two_functions<'j>(jit: &'j mut Jit) -> JitState<'j> {
+        let mut js = jit.new_state();
+        js.name(Some("perf_first")).unwrap();
+        js.note(Some("first.rs"), 10).unwrap();
+        js.prolog();
+        js.reti(1);
+        js.epilog();
+        js.name(Some("perf_second")).unwrap();
+        js.note(Some("second.rs"), 20).unwrap();
+        js.prolog();
+        js.movi(Reg::R(0), 2);
+        js.retr(Reg::R(0));
+        js.epilog();
+        js
+    }
+
+    #[test]
+    fn perf_map() {
+        let mut jit = Jit::new();
+        let js = two_functions(&mut jit).emit().unwrap();
+        let path = Remove(format!("/tmp/perf-{}.map", process::id()).into());
+        PerfMap::new().unwrap().record(&js).unwrap();
+
+        let functions = js.notes().functions();
+        let map = std::fs::read_to_string(&path.0).unwrap();
+        for function in &functions {
+            let line = format!(
+                "{:x} {:x} {}",
+                function.start, function.size, function.name.as_ref().unwrap(),
+            );
+            assert!(map.lines().any(|l| l == line), "{} not in {}", line, map);
+        }
+        assert_eq!(functions[0].start + functions[0].size, functions[1].start);
+    }
+
+    #[test]
+    fn jitdump() {
+        let mut jit = Jit::new();
+        let js = two_functions(&mut jit).emit().unwrap();
+        let dir = std::env::temp_dir();
+        let path = Remove(dir.join(format!("jit-{}.dump", process::id())));
+        PerfMap::jitdump(&dir).unwrap().record(&js).unwrap();
+
+        let dump = std::fs::read(&path.0).unwrap();
+        let u32_at = |at: usize| u32::from_ne_bytes(dump[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), JITDUMP_MAGIC);
+        assert_eq!(u32_at(12), u32::from(elf::MACHINE));
+
+        let mut ids = Vec::new();
+        let mut at = JITDUMP_HEADER_SIZE as usize;
+        while at < dump.len() {
+            ids.push(u32_at(at));
+            at += u32_at(at + 4) as usize;
+        }
+        assert_eq!(at, dump.len());
+        assert_eq!(ids, vec![
+            JIT_CODE_DEBUG_INFO, JIT_CODE_LOAD,
+            JIT_CODE_DEBUG_INFO, JIT_CODE_LOAD,
+            JIT_CODE_CLOSE,
+        ]);
+    }
+}
@@ -0,0 +1,50 @@
+//! The phases that a `JitState` moves through.
+//!
+//! A state starts out `Building`, where instructions can be added. `emit`
+//! moves it to `Emitted`, where the addresses of its nodes and its notes can
+//! be queried. `clear_state` then moves it to `Cleared`, which releases
+//! lightning's bookkeeping and only holds the code. Each phase only provides
+//! the methods that are valid in it.
+
+use crate::{CompiledCode, JitPointer};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A phase of a `JitState`.
+///
+/// This trait is sealed; it cannot be implemented outside this crate.
+pub trait Phase: private::Sealed {}
+
+/// A phase in which lightning still holds the nodes of the state.
+///
+/// This trait is sealed; it cannot be implemented outside this crate.
+pub trait Uncleared: Phase {}
+
+/// Instructions are being added to the state.
+#[derive(Debug)]
+pub struct Building;
+
+/// The state has been emitted into code with an entry point of type `F`.
+#[derive(Debug)]
+pub struct Emitted<F = JitPointer> {
+    pub(crate) code: CompiledCode<F>,
+}
+
+/// The state has been cleared, and only holds the code.
+#[derive(Debug)]
+pub struct Cleared<F = JitPointer> {
+    pub(crate) code: CompiledCode<F>,
+}
+
+impl private::Sealed for Building {}
+impl<F> private::Sealed for Emitted<F> {}
+impl<F> private::Sealed for Cleared<F> {}
+
+impl Phase for Building {}
+impl<F> Phase for Emitted<F> {}
+impl<F> Phase for Cleared<F> {}
+
+impl Uncleared for Building {}
+impl<F> Uncleared for Emitted<F> {}
@@ -22,6 +22,7 @@
 //!     ) -> JitNode<'j> {
 //!         JitNode {
 //!             node: _jit_new_node_ww(self.state, c, u, v),
+//!             state: self.id,
 //!             phantom: std::marker::PhantomData,
 //!         }
 //!     }
@@ -237,7 +238,8 @@ macro_rules! jit_entry_for_node {
                 func = [{ $entry }]
                 body = [{
                     JitNode {
-                        node: $invokes( self.state $( ,$outarg )* ),
+                        node: $invokes( *self.raw() $( ,$outarg )* ),
+                        state: self.id,
                         phantom: std::marker::PhantomData,
                     }
                 }]
@@ -289,22 +291,6 @@ macro_rules! jit_entry_for_node {
 }
 
 macro_rules! jit_entry_non_node {
-    {
-        $caller:tt
-        decl = [{ $entry:ident( $( $inarg:ident ),* ) }]
-        root = [{ destroy_state }]
-        parts = [{ $stem:ident $( $suffix:ident )* }]
-        invokes = [{ $invokes:ident( _jit $( , $outarg:ident )* ) }]
-    } => {
-        make_func! {
-            func = [{ $entry }]
-            body = [{ $invokes( self.state, $( $outarg ),* ) }]
-            rettype = [{ () }]
-            parmhead = [{ self, }] // Takes `self` by move, NOT by reference
-            parmnames = [{ }]
-            parmtypes = [{ }]
-        }
-    };
     { $( $tokens:tt )* } => {
         // Ignore these for now.
     };
@@ -0,0 +1,337 @@
+//! A process-wide registry of the functions in live emitted code.
+//!
+//! Every `CompiledCode` adds the functions it holds here when it is created,
+//! and removes them when it is dropped. Each function is the code from a
+//! `JitState::name` up to the next one; code emitted without names is recorded
+//! as a single anonymous function.
+//!
+//! `lookup` neither locks nor allocates, so it can be called from signal
+//! handlers and panic hooks, for instance to name the frames of a
+//! `std::backtrace::Backtrace` that land in generated code. It scans every
+//! entry, which suits the occasional query rather than a hot path.
+//!
+//! Names are interned and shared with the `Symbol`s that `lookup` returns, so
+//! a `Symbol` keeps its name after its code is dropped. The registry lets go
+//! of a name once no function is registered with it and no `lookup` that may
+//! be reading it is still running.
+
+use crate::types::StateId;
+use crate::JitPointer;
+
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The number of entries in each block of the registry.
+const BLOCK_SIZE: usize = 64;
+
+/// A function of live emitted code, as returned by `lookup`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    /// The address of the first instruction of the function.
+    pub start: JitPointer,
+    /// The address just past the function.
+    pub end: JitPointer,
+    /// The name given to the function by `JitState::name`.
+    pub name: Option<Arc<str>>,
+    /// The `entry_address` of the `CompiledCode` holding the function.
+    pub code: JitPointer,
+    /// The state that emitted the function; see `CompiledCode::state_id`.
+    pub state: StateId,
+}
+
+/// An entry of the registry, guarded by a sequence number that is odd while
+/// the entry is being written. An `end` of zero marks an unused entry.
+#[derive(Debug, Default)]
+struct Slot {
+    sequence: AtomicUsize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    name: AtomicPtr<Arc<str>>,
+    code: AtomicUsize,
+    state: AtomicUsize,
+}
+
+/// A block of entries. Blocks are linked in a list and never freed, so that
+/// readers can walk them without locking.
+#[derive(Debug)]
+struct Block {
+    slots: Box<[Slot]>,
+    next: AtomicPtr<Block>,
+}
+
+static HEAD: AtomicPtr<Block> = AtomicPtr::new(ptr::null_mut());
+
+// The number of running `lookup`s.
+static READERS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    // Serializes writers, and interns the names they store.
+    static ref NAMES: Mutex<Names> = Mutex::new(Names::default());
+}
+
+/// The names of registered functions.
+#[derive(Debug, Default)]
+struct Names {
+    // Each name, with the box whose address the entries store and the number
+    // of entries that store it.
+    interned: HashMap<Arc<str>, (Box<Arc<str>>, usize)>,
+    // Names that no entry stores any more, but that a running `lookup` may
+    // still be reading. They keep their boxes, and so their addresses.
+    #[allow(clippy::vec_box)]
+    retired: Vec<Box<Arc<str>>>,
+}
+
+impl Names {
+    /// Returns the address of `name` for an entry to store.
+    fn acquire(&mut self, name: &str) -> *mut Arc<str> {
+        if let Some((boxed, uses)) = self.interned.get_mut(name) {
+            *uses += 1;
+            return &mut **boxed;
+        }
+        let name: Arc<str> = name.into();
+        let mut boxed = Box::new(Arc::clone(&name));
+        let at: *mut Arc<str> = &mut *boxed;
+        self.interned.insert(name, (boxed, 1));
+        at
+    }
+
+    /// Gives back a name returned by `acquire`, once its entry is cleared.
+    fn release(&mut self, at: *mut Arc<str>) {
+        let name = Arc::clone(unsafe { &*at });
+        let unused = match self.interned.get_mut(&name) {
+            Some((_, uses)) => {
+                *uses -= 1;
+                *uses == 0
+            }
+            None => false,
+        };
+        if unused {
+            let (boxed, _) = self.interned.remove(&name).unwrap();
+            self.retired.push(boxed);
+        }
+    }
+
+    /// Frees the retired names if no `lookup` is running. One that starts
+    /// from now on cannot find them, since their entries were cleared first.
+    fn reclaim(&mut self) {
+        if self.retired.is_empty() {
+            return;
+        }
+        fence(Ordering::SeqCst);
+        if READERS.load(Ordering::SeqCst) == 0 {
+            self.retired.clear();
+        }
+    }
+}
+
+/// The entries of a `CompiledCode`, which are removed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    slots: Vec<&'static Slot>,
+}
+
+impl Registration {
+    /// Adds a function for each `(start, size, name)` of the code whose entry
+    /// address is `code`, emitted by the state `state`.
+    pub(crate) fn new<'n>(
+        code: usize,
+        state: StateId,
+        functions: impl IntoIterator<Item = (usize, usize, Option<&'n str>)>,
+    ) -> Registration {
+        let mut names = NAMES.lock().unwrap();
+        names.reclaim();
+        let slots = functions
+            .into_iter()
+            .map(|(start, size, name)| {
+                let name = name.map_or(ptr::null_mut(), |name| names.acquire(name));
+                let slot = free_slot();
+                slot.write(start, start + size, name, code, state.0);
+                slot
+            })
+            .collect();
+        Registration { slots }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut names = NAMES.lock().unwrap();
+        for slot in &self.slots {
+            let name = slot.name.load(Ordering::Relaxed);
+            slot.write(0, 0, ptr::null_mut(), 0, 0);
+            if !name.is_null() {
+                names.release(name);
+            }
+        }
+        names.reclaim();
+    }
+}
+
+impl Slot {
+    /// Stores an entry. The caller must hold the `NAMES` lock.
+    fn write(&self, start: usize, end: usize, name: *mut Arc<str>, code: usize, state: usize) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.start.store(start, Ordering::Relaxed);
+        self.end.store(end, Ordering::Relaxed);
+        self.name.store(name, Ordering::Relaxed);
+        self.code.store(code, Ordering::Relaxed);
+        self.state.store(state, Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    /// Reads the entry if it covers `pc`. An entry that is being written by
+    /// an interrupted thread is skipped rather than waited for.
+    fn read(&self, pc: usize) -> Option<Symbol> {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence & 1 != 0 {
+                return None;
+            }
+            let start = self.start.load(Ordering::Relaxed);
+            let end = self.end.load(Ordering::Relaxed);
+            let name = self.name.load(Ordering::Relaxed);
+            let code = self.code.load(Ordering::Relaxed);
+            let state = self.state.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) != sequence {
+                continue;
+            }
+
+            if pc < start || pc >= end {
+                return None;
+            }
+            // The name is not freed while a `lookup` runs, even if the entry
+            // has been cleared since it was read.
+            let name = if name.is_null() { None } else { Some(Arc::clone(unsafe { &*name })) };
+            return Some(Symbol {
+                start: start as JitPointer,
+                end: end as JitPointer,
+                name,
+                code: code as JitPointer,
+                state: StateId(state),
+            });
+        }
+    }
+}
+
+/// Finds an unused entry, adding a block if there is none. The caller must
+/// hold the `NAMES` lock.
+fn free_slot() -> &'static Slot {
+    let mut link = &HEAD;
+    loop {
+        let block = link.load(Ordering::Acquire);
+        if block.is_null() {
+            let block = Box::leak(Box::new(Block {
+                slots: (0..BLOCK_SIZE).map(|_| Slot::default()).collect(),
+                next: AtomicPtr::new(ptr::null_mut()),
+            }));
+            link.store(block, Ordering::Release);
+            return &block.slots[0];
+        }
+        let block: &'static Block = unsafe { &*block };
+        if let Some(slot) = block.slots.iter().find(|s| s.end.load(Ordering::Relaxed) == 0) {
+            return slot;
+        }
+        link = &block.next;
+    }
+}
+
+/// Returns the function of live emitted code that contains `pc`, if any.
+pub fn lookup(pc: JitPointer) -> Option<Symbol> {
+    READERS.fetch_add(1, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+    let found = find(pc as usize);
+    READERS.fetch_sub(1, Ordering::Release);
+    found
+}
+
+/// Scans the entries for the one that contains `pc`.
+fn find(pc: usize) -> Option<Symbol> {
+    let mut block = HEAD.load(Ordering::Acquire);
+    while !block.is_null() {
+        let current = unsafe { &*block };
+        if let Some(symbol) = current.slots.iter().find_map(|slot| slot.read(pc)) {
+            return Some(symbol);
+        }
+        block = current.next.load(Ordering::Acquire);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Jit, Reg};
+
+    #[test]
+    fn add_and_remove() {
+        // Addresses that no emitted code can occupy.
+        static AREA: [u8; 64] = [0; 64];
+        let base = AREA.as_ptr() as usize;
+        let at = |offset: usize| (base + offset) as JitPointer;
+
+        let state = StateId::next();
+        let registration = Registration::new(base, state, vec![
+            (base, 16, Some("registry_first")),
+            (base + 16, 32, None),
+        ]);
+        let first = lookup(at(15)).unwrap();
+        assert_eq!(first.name.as_deref(), Some("registry_first"));
+        assert_eq!((first.start, first.end, first.code), (at(0), at(16), at(0)));
+        assert_eq!(first.state, state);
+        assert_eq!(lookup(at(16)).unwrap().name, None);
+        assert_eq!(lookup(at(48)), None);
+
+        drop(registration);
+        assert_eq!(lookup(at(0)), None);
+        assert_eq!(lookup(at(16)), None);
+        assert_eq!(first.name.as_deref(), Some("registry_first"));
+    }
+
+    #[test]
+    fn frees_names() {
+        static AREA: [u8; 16] = [0; 16];
+        let base = AREA.as_ptr() as usize;
+        let interned = || NAMES.lock().unwrap().interned.contains_key("registry_freed");
+
+        let first = Registration::new(base, StateId::next(), vec![(base, 8, Some("registry_freed"))]);
+        let second = Registration::new(base + 8, StateId::next(), vec![(base + 8, 8, Some("registry_freed"))]);
+        let symbol = lookup(base as JitPointer).unwrap();
+        drop(first);
+        assert!(interned());
+        drop(second);
+        assert!(!interned());
+        assert_eq!(symbol.name.as_deref(), Some("registry_freed"));
+    }
+
+    #[test]
+    fn emitted_functions() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        let first = js.label();
+        js.name(Some("registry_one")).unwrap();
+        js.prolog();
+        js.reti(1);
+        js.epilog();
+        let second = js.label();
+        js.name(Some("registry_two")).unwrap();
+        js.prolog();
+        js.movi(Reg::R(0), 2);
+        js.retr(Reg::R(0));
+        js.epilog();
+
+        let js = js.emit().unwrap();
+        let (first, second) = (js.address(&first), js.address(&second));
+        let one = lookup(first).unwrap();
+        assert_eq!(one.name.as_deref(), Some("registry_one"));
+        assert_eq!(one.code, js.entry_address());
+        assert_eq!(one.state, js.id());
+        assert_eq!(one.state, js.state_id());
+        assert_eq!(one.end, lookup(second).unwrap().start);
+        assert_eq!(lookup(second).unwrap().name.as_deref(), Some("registry_two"));
+    }
+}
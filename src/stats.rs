@@ -0,0 +1,165 @@
+//! Statistics about what lightning produced for a state, as returned by
+//! `JitState::stats`.
+
+use crate::bindings;
+use crate::phase::Emitted;
+use crate::JitState;
+
+use std::fmt;
+
+/// The kinds of instructions that `Stats` counts nodes by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NodeCategory {
+    /// Nodes that emit no instructions of their own, or only the frame:
+    /// labels, notes, names, data, `prolog` and `epilog`.
+    Pseudo,
+    /// Declaring, reading and writing arguments, `allocai` and varargs.
+    Argument,
+    /// Integer arithmetic, comparisons, moves and conversions.
+    Integer,
+    /// Floating-point arithmetic, comparisons, moves and conversions.
+    Float,
+    /// Loads and stores.
+    Memory,
+    /// Branches and jumps.
+    Branch,
+    /// Calls, their arguments and return values, and returns.
+    Call,
+}
+
+impl NodeCategory {
+    /// Every category, in order.
+    pub const ALL: [NodeCategory; 7] = [
+        NodeCategory::Pseudo,
+        NodeCategory::Argument,
+        NodeCategory::Integer,
+        NodeCategory::Float,
+        NodeCategory::Memory,
+        NodeCategory::Branch,
+        NodeCategory::Call,
+    ];
+}
+
+/// Statistics about an emitted state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    nodes: [usize; 7],
+    /// The number of labels.
+    pub labels: usize,
+    /// The number of labels that an instruction before them refers to, such
+    /// as those made with `JitState::forward`.
+    pub forwards: usize,
+    /// The number of instructions patched to refer to a label.
+    pub patches: usize,
+    /// lightning's estimate of the size of the code, from the sizes of the
+    /// instructions in jit_size.c, rounded up to whole pages.
+    pub estimated_code_size: usize,
+    /// The size of the emitted code.
+    pub code_size: usize,
+    /// The size of the constants.
+    pub data_size: usize,
+    /// The size of the notes.
+    pub note_size: usize,
+    /// The bytes requested with `allocai` by each function, in the order of
+    /// their `prolog`s. lightning may reserve more, to align the slots.
+    pub allocai: Vec<usize>,
+}
+
+impl Stats {
+    /// Returns the number of nodes of `category`.
+    pub fn nodes(&self, category: NodeCategory) -> usize {
+        self.nodes[category as usize]
+    }
+
+    /// Returns the number of nodes.
+    pub fn node_count(&self) -> usize {
+        self.nodes.iter().sum()
+    }
+}
+
+impl<'a, F> JitState<'a, Emitted<F>> {
+    /// Gathers statistics about the nodes of this state and the code
+    /// lightning emitted for them. The nodes are counted as lightning left
+    /// them after optimizing.
+    pub fn stats(&self) -> Stats {
+        let mut raw = bindings::lgsys_stats { nodes: [0; 7], labels: 0, forwards: 0, patches: 0, estimated: 0 };
+        let gathered = unsafe { bindings::lgsys_stats(*self.raw(), &mut raw) };
+        assert!(gathered != 0, "out of memory gathering statistics");
+
+        let mut code_size = 0;
+        unsafe { bindings::_jit_get_code(*self.raw(), &mut code_size) };
+
+        let mut nodes = [0; 7];
+        for (count, &raw) in nodes.iter_mut().zip(&raw.nodes) {
+            *count = raw as usize;
+        }
+        Stats {
+            nodes,
+            labels: raw.labels as usize,
+            forwards: raw.forwards as usize,
+            patches: raw.patches as usize,
+            estimated_code_size: raw.estimated as usize,
+            code_size: code_size as usize,
+            data_size: self.data_bytes().len(),
+            note_size: self.note_bytes().len(),
+            allocai: self.allocai.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nodes: {}", self.node_count())?;
+        for &category in &NodeCategory::ALL {
+            write!(f, ", {:?}: {}", category, self.nodes(category))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "labels: {}, forwards: {}, patches: {}", self.labels, self.forwards, self.patches)?;
+        writeln!(f, "code: {} bytes, estimated {} bytes", self.code_size, self.estimated_code_size)?;
+        writeln!(f, "data: {} bytes, notes: {} bytes", self.data_size, self.note_size)?;
+        write!(f, "allocai: {:?}", self.allocai)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Base, Jit, JitWord, Reg};
+
+    #[test]
+    fn counts_nodes() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        let slot = js.allocai(16);
+        let arg = js.arg::<JitWord>();
+        js.getarg(Reg::R(0), &arg);
+        js.stxi(slot.into(), Base::FP, Reg::R(0));
+        let skip = js.forward();
+        let branch = js.beqi(Reg::R(0), 0);
+        js.patch_at(&branch, &skip);
+        js.addi(Reg::R(0), Reg::R(0), 1);
+        js.link(&skip);
+        js.retr(Reg::R(0));
+        js.epilog();
+        js.prolog();
+        js.allocai(4);
+        js.allocai(8);
+        js.reti(0);
+        js.epilog();
+
+        let js = js.emit().unwrap();
+        let stats = js.stats();
+        assert!(stats.nodes(NodeCategory::Pseudo) >= 3);
+        assert!(stats.nodes(NodeCategory::Memory) >= 1);
+        assert!(stats.nodes(NodeCategory::Branch) >= 1);
+        assert_eq!(stats.node_count(), NodeCategory::ALL.iter().map(|&c| stats.nodes(c)).sum());
+        assert!(stats.labels >= 1);
+        assert!(stats.forwards >= 1);
+        assert!(stats.patches >= 1);
+        assert_eq!(stats.code_size, js.code_bytes().len());
+        assert!(stats.estimated_code_size >= stats.code_size);
+        assert_eq!(stats.allocai, [16, 12]);
+        assert!(stats.to_string().starts_with("nodes: "));
+    }
+}
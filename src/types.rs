@@ -1,19 +1,147 @@
 use crate::bindings;
+use crate::{CompiledCode, Jit, JitFn, JitState};
+use crate::node::Arg;
+use crate::{Error, Result};
 use std::ptr::null_mut;
 use std::ffi::c_void;
 
-#[derive(Copy,Clone,PartialEq,Eq,Debug)]
-pub enum Reg {
+/// A general-purpose (integer) register: a caller-saved `R` register or a
+/// callee-saved `V` register.
+///
+/// A `Reg` always names a register that exists on the current target.
+#[derive(Copy,Clone,PartialEq,Eq,Hash)]
+pub struct Reg(Gpr);
+
+#[derive(Copy,Clone,PartialEq,Eq,Hash,Debug)]
+enum Gpr {
     R(bindings::jit_gpr_t),
     V(bindings::jit_gpr_t),
-    F(bindings::jit_gpr_t),
+}
+
+/// A floating-point register.
+///
+/// An `FReg` always names a register that exists on the current target.
+#[derive(Copy,Clone,PartialEq,Eq,Hash)]
+pub struct FReg(bindings::jit_fpr_t);
+
+/// Checks `index` against the number of registers in `class`.
+fn check_reg(class: char, index: bindings::jit_gpr_t, num: bindings::jit_gpr_t) -> Result<bindings::jit_gpr_t> {
+    if index >= 0 && index < num {
+        Ok(index)
+    } else {
+        Err(Error::InvalidRegister { class, index })
+    }
+}
+
+impl Reg {
+    /// Returns the caller-saved register `R{index}`, or an error if the
+    /// target does not have it.
+    pub fn try_r(index: bindings::jit_gpr_t) -> Result<Reg> {
+        check_reg('R', index, Jit::r_num()).map(|i| Reg(Gpr::R(i)))
+    }
+
+    /// Returns the callee-saved register `V{index}`, or an error if the
+    /// target does not have it.
+    pub fn try_v(index: bindings::jit_gpr_t) -> Result<Reg> {
+        check_reg('V', index, Jit::v_num()).map(|i| Reg(Gpr::V(i)))
+    }
+
+    /// Returns the caller-saved register `R{index}`.
+    ///
+    /// # Panics
+    /// If the target does not have the register; see `try_r`.
+    #[allow(non_snake_case)]
+    pub fn R(index: bindings::jit_gpr_t) -> Reg {
+        Reg::try_r(index).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Returns the callee-saved register `V{index}`.
+    ///
+    /// # Panics
+    /// If the target does not have the register; see `try_v`.
+    #[allow(non_snake_case)]
+    pub fn V(index: bindings::jit_gpr_t) -> Reg {
+        Reg::try_v(index).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Iterates over every `R` register on the current target.
+    pub fn all_r() -> impl Iterator<Item = Reg> + Clone {
+        (0..Jit::r_num()).map(|i| Reg(Gpr::R(i)))
+    }
+
+    /// Iterates over every `V` register on the current target.
+    pub fn all_v() -> impl Iterator<Item = Reg> + Clone {
+        (0..Jit::v_num()).map(|i| Reg(Gpr::V(i)))
+    }
+}
+
+impl std::fmt::Debug for Reg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FReg {
+    /// Returns the register `F{index}`, or an error if the target does not
+    /// have it.
+    pub fn try_f(index: bindings::jit_fpr_t) -> Result<FReg> {
+        check_reg('F', index, Jit::f_num()).map(FReg)
+    }
+
+    /// Returns the register `F{index}`.
+    ///
+    /// # Panics
+    /// If the target does not have the register; see `try_f`.
+    #[allow(non_snake_case)]
+    pub fn F(index: bindings::jit_fpr_t) -> FReg {
+        FReg::try_f(index).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Iterates over every `F` register on the current target.
+    pub fn all() -> impl Iterator<Item = FReg> + Clone {
+        (0..Jit::f_num()).map(FReg)
+    }
+}
+
+impl std::fmt::Debug for FReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "F({})", self.0)
+    }
+}
+
+/// The base address of a load or store: a general-purpose register, or the
+/// frame pointer.
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
+pub enum Base {
+    Reg(Reg),
     FP,
 }
 
+impl From<Reg> for Base {
+    fn from(reg: Reg) -> Base {
+        Base::Reg(reg)
+    }
+}
+
+#[derive(Clone,Debug)]
 pub struct JitNode<'a> {
     pub(crate) node:  *mut bindings::jit_node_t,
+    pub(crate) state: StateId,
     pub(crate) phantom: std::marker::PhantomData<&'a ()>,
-    //pub(crate) state: &'a JitState<'a>,
+}
+
+/// Uniquely identifies a `JitState` for the lifetime of the process, so that
+/// nodes can be checked against the state they are used with, and emitted
+/// code traced back to the state it came from; see `JitState::id`.
+#[derive(Copy,Clone,PartialEq,Eq,Hash,Debug)]
+pub struct StateId(pub(crate) usize);
+
+impl StateId {
+    pub(crate) fn next() -> StateId {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        StateId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 pub type JitWord = bindings::jit_word_t;
@@ -26,33 +154,37 @@ pub(crate) trait ToFFI {
     type Type;
     #[must_use]
     fn to_ffi(&self) -> Self::Type;
+
+    /// Panics if `self` may not be passed to the state identified by `state`.
+    fn check_state(&self, _state: StateId) {}
 }
 
 impl ToFFI for Reg {
     type Type = bindings::jit_gpr_t;
 
-    //TODO: safe conversion
+    fn to_ffi(&self) -> Self::Type {
+        match self.0 {
+            Gpr::R(i) => unsafe { bindings::lgsys_jit_r(i) },
+            Gpr::V(i) => unsafe { bindings::lgsys_jit_v(i) },
+        }
+    }
+}
+
+impl ToFFI for FReg {
+    type Type = bindings::jit_fpr_t;
+
+    fn to_ffi(&self) -> Self::Type {
+        unsafe { bindings::lgsys_jit_f(self.0) }
+    }
+}
+
+impl ToFFI for Base {
+    type Type = bindings::jit_gpr_t;
+
     fn to_ffi(&self) -> Self::Type {
         match self {
-            Reg::R(i) => if *i < unsafe { bindings::lgsys_JIT_R_NUM() } {
-                unsafe { bindings::lgsys_jit_r(*i) }
-            } else {
-                panic!("register 'R{}' is not supported", *i);
-            },
-
-            Reg::V(i) => if *i < unsafe { bindings::lgsys_JIT_V_NUM() } {
-                unsafe { bindings::lgsys_jit_v(*i) }
-            } else {
-                panic!("register 'V{}' is not supported", i);
-            },
-
-            Reg::F(i) => if *i < unsafe { bindings::lgsys_JIT_F_NUM() } {
-                unsafe { bindings::lgsys_jit_f(*i) }
-            } else {
-                panic!("register 'F{}' is not supported", i);
-            },
-
-            Reg::FP => unsafe { bindings::lgsys_JIT_FP },
+            Base::Reg(reg) => reg.to_ffi(),
+            Base::FP => unsafe { bindings::lgsys_JIT_FP },
         }
     }
 }
@@ -63,19 +195,227 @@ impl<'a> ToFFI for JitNode<'a> {
     fn to_ffi(&self) -> Self::Type {
         self.node
     }
+
+    fn check_state(&self, state: StateId) {
+        assert_eq!(self.state, state, "node belongs to a different JitState");
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Scalar types that can cross the boundary into and out of generated code:
+/// the integer types up to 64 bits, `f32`, `f64` and raw pointers.
+///
+/// This trait is sealed; it cannot be implemented outside this crate. Its
+/// methods select the lightning entry points that match each type, and are
+/// used by `JitState::function` and the generic `arg`, `getarg`, `putargr`
+/// and `putargi` methods.
+pub trait FFISafe: Copy + private::Sealed {
+    /// The class of register that holds a value of this type: `Reg` or
+    /// `FReg`.
+    type Reg: Copy;
+
+    #[doc(hidden)]
+    fn param_reg(regs: &mut ParamRegs) -> Option<Self::Reg>;
+    #[doc(hidden)]
+    fn arg<'a>(js: &mut JitState<'a>) -> Arg<'a, Self>;
+    #[doc(hidden)]
+    fn getarg<'a>(js: &mut JitState<'a>, reg: Self::Reg, node: &Arg<'a, Self>);
+    #[doc(hidden)]
+    fn putargr<'a>(js: &mut JitState<'a>, reg: Self::Reg, node: &Arg<'a, Self>);
+    #[doc(hidden)]
+    fn putargi<'a>(js: &mut JitState<'a>, imm: Self, node: &Arg<'a, Self>);
+    #[doc(hidden)]
+    fn retr(js: &mut JitState<'_>, reg: Self::Reg);
+    #[doc(hidden)]
+    fn reti(js: &mut JitState<'_>, imm: Self);
+}
+
+/// Implements `FFISafe` for integral and pointer types, given the `getarg`
+/// variant that reads an argument of that type.
+macro_rules! ffi_safe_integral {
+    ( $( $typ:ty => $getarg:ident ),* ) => {
+        $( ffi_safe_integral!(@impl [] $typ, $getarg); )*
+    };
+    ( @impl [ $( $gen:ident )? ] $typ:ty, $getarg:ident ) => {
+        impl $( <$gen> )? FFISafe for $typ {
+            type Reg = Reg;
+
+            fn param_reg(regs: &mut ParamRegs) -> Option<Reg> { regs.next_int() }
+            fn arg<'a>(js: &mut JitState<'a>) -> Arg<'a, Self> { js.arg_word() }
+            fn getarg<'a>(js: &mut JitState<'a>, reg: Reg, node: &Arg<'a, Self>) {
+                js.getarg_with($getarg, reg, node)
+            }
+            fn putargr<'a>(js: &mut JitState<'a>, reg: Reg, node: &Arg<'a, Self>) { js.putargr_word(reg, node) }
+            fn putargi<'a>(js: &mut JitState<'a>, imm: Self, node: &Arg<'a, Self>) {
+                js.putargi_word(imm as JitWord, node)
+            }
+            fn retr(js: &mut JitState<'_>, reg: Reg) { js.retr(reg) }
+            fn reti(js: &mut JitState<'_>, imm: Self) { js.reti(imm as JitWord) }
+        }
+    };
+}
+
+// The `getarg` variant for word-sized arguments.
+#[cfg(target_pointer_width = "64")]
+use bindings::_jit_getarg_l as _jit_getarg_w;
+#[cfg(target_pointer_width = "32")]
+use bindings::_jit_getarg_i as _jit_getarg_w;
+
+use bindings::{_jit_getarg_c, _jit_getarg_uc, _jit_getarg_s, _jit_getarg_us, _jit_getarg_i};
+#[cfg(target_pointer_width = "64")]
+use bindings::{_jit_getarg_ui, _jit_getarg_l};
+
+ffi_safe_integral! {
+    i8 => _jit_getarg_c,
+    u8 => _jit_getarg_uc,
+    i16 => _jit_getarg_s,
+    u16 => _jit_getarg_us,
+    i32 => _jit_getarg_i
+}
+ffi_safe_integral!(@impl [T] *const T, _jit_getarg_w);
+ffi_safe_integral!(@impl [T] *mut T, _jit_getarg_w);
+ffi_safe_integral!(isize => _jit_getarg_w, usize => _jit_getarg_w);
+
+#[cfg(target_pointer_width = "64")]
+ffi_safe_integral!(u32 => _jit_getarg_ui, i64 => _jit_getarg_l, u64 => _jit_getarg_l);
+// lightning cannot pass 64-bit integers on 32-bit targets.
+#[cfg(target_pointer_width = "32")]
+ffi_safe_integral!(u32 => _jit_getarg_i);
+
+impl FFISafe for f32 {
+    type Reg = FReg;
+
+    fn param_reg(regs: &mut ParamRegs) -> Option<FReg> { regs.next_float() }
+    fn arg<'a>(js: &mut JitState<'a>) -> Arg<'a, Self> { js.arg_f() }
+    fn getarg<'a>(js: &mut JitState<'a>, reg: FReg, node: &Arg<'a, Self>) { js.getarg_f(reg, node) }
+    fn putargr<'a>(js: &mut JitState<'a>, reg: FReg, node: &Arg<'a, Self>) { js.putargr_f(reg, node) }
+    fn putargi<'a>(js: &mut JitState<'a>, imm: Self, node: &Arg<'a, Self>) { js.putargi_f(imm, node) }
+    fn retr(js: &mut JitState<'_>, reg: FReg) { js.retr_f(reg) }
+    fn reti(js: &mut JitState<'_>, imm: Self) { js.reti_f(imm) }
+}
+
+impl FFISafe for f64 {
+    type Reg = FReg;
+
+    fn param_reg(regs: &mut ParamRegs) -> Option<FReg> { regs.next_float() }
+    fn arg<'a>(js: &mut JitState<'a>) -> Arg<'a, Self> { js.arg_d() }
+    fn getarg<'a>(js: &mut JitState<'a>, reg: FReg, node: &Arg<'a, Self>) { js.getarg_d(reg, node) }
+    fn putargr<'a>(js: &mut JitState<'a>, reg: FReg, node: &Arg<'a, Self>) { js.putargr_d(reg, node) }
+    fn putargi<'a>(js: &mut JitState<'a>, imm: Self, node: &Arg<'a, Self>) { js.putargi_d(imm, node) }
+    fn retr(js: &mut JitState<'_>, reg: FReg) { js.retr_d(reg) }
+    fn reti(js: &mut JitState<'_>, imm: Self) { js.reti_d(imm) }
 }
 
-pub(crate) trait FFISafe: Copy{}
+impl<T: FFISafe> private::Sealed for T {}
+
+/// Types that can be returned from generated code: any `FFISafe` type, or
+/// `()`.
+///
+/// This trait is sealed; it cannot be implemented outside this crate.
+pub trait FFIReturn: private::Sealed {}
+
+impl<T: FFISafe> FFIReturn for T {}
+impl private::Sealed for () {}
+impl FFIReturn for () {}
 
-// this is dumb, blame rust
-impl FFISafe for JitPointer{}
-impl FFISafe for i32{}
-impl FFISafe for u32{}
-impl FFISafe for i64{}
-impl FFISafe for u64{}
-impl FFISafe for f32{}
-impl FFISafe for f64{}
+/// Function pointer types that generated code can be cast to: `extern "C"`
+/// and `unsafe extern "C"` functions of up to twelve `FFISafe` parameters,
+/// returning an `FFIReturn` type.
+///
+/// This trait is sealed; it cannot be implemented outside this crate.
+pub trait JitFnPtr: Copy + private::Sealed {
+    /// Reinterprets `ptr` as a function pointer of this type.
+    ///
+    /// # Safety
+    /// `ptr` must point to code that has this function's signature.
+    unsafe fn from_pointer(ptr: JitPointer) -> Self;
+
+    /// The registers holding the parameters inside the function body, as a
+    /// tuple.
+    type Params: Copy;
+    /// The return type.
+    type Output: FFIReturn;
+
+    #[doc(hidden)]
+    fn param_regs(regs: &mut ParamRegs) -> Option<Self::Params>;
+    #[doc(hidden)]
+    fn load_params(js: &mut JitState<'_>, params: Self::Params);
+}
 
+macro_rules! impl_jit_fn_ptr {
+    ( $fn:ty; $( #[$doc:meta] )* $( $qual:ident )?; $( $name:ident: $arg:ident ),* ) => {
+        impl<R: FFIReturn $(, $arg: FFISafe )*> private::Sealed for $fn {}
+
+        impl<R: FFIReturn $(, $arg: FFISafe )*> JitFnPtr for $fn {
+            unsafe fn from_pointer(ptr: JitPointer) -> Self {
+                *(&ptr as *const JitPointer as *const Self)
+            }
+
+            type Params = ( $( $arg::Reg, )* );
+            type Output = R;
+
+            #[allow(unused_variables)]
+            fn param_regs(regs: &mut ParamRegs) -> Option<Self::Params> {
+                Some(( $( $arg::param_reg(regs)?, )* ))
+            }
+
+            #[allow(unused_variables)]
+            fn load_params(js: &mut JitState<'_>, params: Self::Params) {
+                let ( $( $name, )* ) = params;
+                $( {
+                    let node = $arg::arg(js);
+                    $arg::getarg(js, $name, &node);
+                } )*
+            }
+        }
+
+        impl<R: FFIReturn $(, $arg: FFISafe )*> CompiledCode<$fn> {
+            /// Calls the entry point of the code.
+            $( #[$doc] )*
+            #[allow(clippy::too_many_arguments)]
+            pub $( $qual )? fn call(&self $(, $name: $arg )*) -> R {
+                (self.entry)($( $name ),*)
+            }
+        }
+
+        impl<'c, R: FFIReturn $(, $arg: FFISafe )*> JitFn<'c, $fn> {
+            /// Calls the function.
+            $( #[$doc] )*
+            #[allow(clippy::too_many_arguments)]
+            pub $( $qual )? fn call(&self $(, $name: $arg )*) -> R {
+                (self.func)($( $name ),*)
+            }
+        }
+    };
+    ( $( $name:ident: $arg:ident ),* ) => {
+        impl_jit_fn_ptr!(extern "C" fn($( $arg ),*) -> R; ; $( $name: $arg ),*);
+        impl_jit_fn_ptr!(
+            unsafe extern "C" fn($( $arg ),*) -> R;
+            #[doc = ""]
+            #[doc = "# Safety"]
+            #[doc = "The caller must uphold whatever the generated code requires."]
+            unsafe;
+            $( $name: $arg ),*
+        );
+    };
+}
+
+impl_jit_fn_ptr!();
+impl_jit_fn_ptr!(a: A);
+impl_jit_fn_ptr!(a: A, b: B);
+impl_jit_fn_ptr!(a: A, b: B, c: C);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K);
+impl_jit_fn_ptr!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L);
 
 impl<T: FFISafe> ToFFI for T {
     type Type = T;
@@ -84,3 +424,29 @@ impl<T: FFISafe> ToFFI for T {
         *self
     }
 }
+
+/// Assigns registers to the parameters of a function built by
+/// `JitState::function`.
+///
+/// Integer parameters use the callee-saved `V` registers first, followed by
+/// the `R` registers. Floating-point parameters use the `F` registers. Once a
+/// class runs out, no more parameters of that class can be given registers.
+#[derive(Debug, Default)]
+pub struct ParamRegs {
+    ints: bindings::jit_gpr_t,
+    floats: bindings::jit_fpr_t,
+}
+
+impl ParamRegs {
+    fn next_int(&mut self) -> Option<Reg> {
+        let n = self.ints;
+        self.ints += 1;
+        Reg::all_v().chain(Reg::all_r()).nth(n as usize)
+    }
+
+    fn next_float(&mut self) -> Option<FReg> {
+        let n = self.floats;
+        self.floats += 1;
+        FReg::all().nth(n as usize)
+    }
+}
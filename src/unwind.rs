@@ -0,0 +1,444 @@
+//! Unwind information for emitted functions.
+//!
+//! With the `unwind` feature, the `CompiledCode` of a state that used `prolog`
+//! and `epilog` registers an `.eh_frame` section with the unwinder through
+//! `__register_frame`, so that panics, backtraces and C++ exceptions can pass
+//! through the generated functions. It is deregistered when the
+//! `CompiledCode` is dropped.
+//!
+//! Each function is described by a frame description entry from the start of
+//! its code up to its epilog, giving the frame that lightning's prolog builds
+//! on the target: the canonical frame address relative to the frame pointer,
+//! and where the return address and each callee-saved register were stored.
+//! The rules hold once the prolog has set up the frame pointer, which is
+//! wherever the function can call out; they are not exact within the prolog
+//! and epilog themselves. Only the x86-64 (System V) and AArch64 frame layouts
+//! are known; on other targets no unwind information is registered.
+
+use crate::bindings;
+use crate::elf::Writer;
+
+use std::mem;
+use std::ops::Range;
+
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_OFFSET: u8 = 0x80;
+
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// The frame built by lightning's prolog on x86-64, in DWARF register numbers.
+#[cfg(all(target_arch = "x86_64", not(windows)))]
+mod frame {
+    pub(super) const CODE_ALIGNMENT: u64 = 1;
+    pub(super) const RETURN_ADDRESS: u8 = 16;
+    /// The CFA is this many bytes above `%rbp`.
+    pub(super) const CFA: (u8, u64) = (6, 56);
+    /// Registers stored in every frame, with their distance below the CFA.
+    pub(super) const FIXED: &[(u8, u64)] = &[(16, 8), (6, 56)];
+    /// Registers stored only when the function uses them, in the order of
+    /// the bits of `lgsys_frame::saved`.
+    pub(super) const SAVED: &[(u8, u64)] = &[(3, 48), (12, 40), (13, 32), (14, 24), (15, 16)];
+}
+
+/// The frame built by lightning's prolog on AArch64, in DWARF register
+/// numbers.
+#[cfg(target_arch = "aarch64")]
+mod frame {
+    pub(super) const CODE_ALIGNMENT: u64 = 4;
+    pub(super) const RETURN_ADDRESS: u8 = 30;
+    /// The CFA is this many bytes above `x29`.
+    pub(super) const CFA: (u8, u64) = (29, 160);
+    /// Registers stored in every frame, with their distance below the CFA.
+    pub(super) const FIXED: &[(u8, u64)] = &[(29, 160), (30, 152)];
+    /// Registers stored only when the function uses them, in the order of
+    /// the bits of `lgsys_frame::saved`: `x19` to `x28`, then `d8` to `d15`.
+    pub(super) const SAVED: &[(u8, u64)] = &[
+        (19, 144), (20, 136), (21, 128), (22, 120), (23, 112),
+        (24, 104), (25, 96), (26, 88), (27, 80), (28, 72),
+        (72, 64), (73, 56), (74, 48), (75, 40), (76, 32), (77, 24), (78, 16), (79, 8),
+    ];
+}
+
+/// The frame layout is unknown; `lgsys_frames` finds no functions here.
+#[cfg(not(any(all(target_arch = "x86_64", not(windows)), target_arch = "aarch64")))]
+mod frame {
+    pub(super) const CODE_ALIGNMENT: u64 = 1;
+    pub(super) const RETURN_ADDRESS: u8 = 0;
+    pub(super) const CFA: (u8, u64) = (0, 0);
+    pub(super) const FIXED: &[(u8, u64)] = &[];
+    pub(super) const SAVED: &[(u8, u64)] = &[];
+}
+
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+/// An `.eh_frame` section registered with the unwinder, which is
+/// deregistered when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    // The unwinder reads the section from this memory for as long as it is
+    // registered; it is kept in words for the alignment of its entries.
+    section: Box<[usize]>,
+    // The offsets of the entries passed to `__register_frame`.
+    entries: Vec<usize>,
+}
+
+/// A function, from its first instruction up to its epilog.
+#[derive(Debug, PartialEq)]
+struct Frame {
+    start: usize,
+    end: usize,
+    saved: usize,
+}
+
+impl Registration {
+    /// Registers unwind information for the functions of the emitted `state`
+    /// within `code`, which starts where its code does or at a function, if
+    /// it has any.
+    pub(crate) unsafe fn new(state: *mut bindings::jit_state_t, code: Range<usize>) -> Option<Registration> {
+        let count = bindings::lgsys_frames(state, std::ptr::null_mut(), 0);
+        if count == 0 {
+            return None;
+        }
+        let mut raw = Vec::with_capacity(count as usize);
+        bindings::lgsys_frames(state, raw.as_mut_ptr(), count);
+        raw.set_len(count as usize);
+
+        let mut frames = Vec::with_capacity(raw.len());
+        let mut previous = code.start;
+        for frame in raw {
+            let epilog = frame.epilog as usize;
+            let (start, end) = (previous.max(code.start), epilog.min(code.end));
+            if start < end {
+                frames.push(Frame { start, end, saved: frame.saved as usize });
+            }
+            previous = epilog;
+        }
+        if frames.is_empty() {
+            return None;
+        }
+
+        let (bytes, fdes) = eh_frame(&frames);
+        let mut section = vec![0usize; bytes.len() / WORD + 1].into_boxed_slice();
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), section.as_mut_ptr() as *mut u8, bytes.len());
+
+        // libgcc takes a whole section, where the unwinder of macOS takes a
+        // single entry.
+        let entries = if cfg!(target_os = "macos") { fdes } else { vec![0] };
+        for &at in &entries {
+            __register_frame((section.as_ptr() as *const u8).add(at));
+        }
+        Some(Registration { section, entries })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        for &at in &self.entries {
+            unsafe { __deregister_frame((self.section.as_ptr() as *const u8).add(at)) };
+        }
+    }
+}
+
+/// Builds an `.eh_frame` section with an entry for each of `frames`, ending
+/// with a terminator. Also returns the offset of each frame's entry.
+fn eh_frame(frames: &[Frame]) -> (Vec<u8>, Vec<usize>) {
+    let mut out = Writer::default();
+
+    // The common information entry holds the rules shared by every frame.
+    out.u32(0);
+    out.u32(0); // CIE id
+    out.u8(1); // version
+    out.c_str("zR");
+    out.uleb128(frame::CODE_ALIGNMENT);
+    out.sleb128(-(WORD as i64));
+    out.u8(frame::RETURN_ADDRESS);
+    out.uleb128(1); // augmentation data length
+    out.u8(DW_EH_PE_ABSPTR);
+    out.u8(DW_CFA_DEF_CFA);
+    out.uleb128(u64::from(frame::CFA.0));
+    out.uleb128(frame::CFA.1);
+    for &(register, offset) in frame::FIXED {
+        offset_rule(&mut out, register, offset);
+    }
+    finish_entry(&mut out, 0);
+
+    let mut fdes = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let at = out.len();
+        fdes.push(at);
+        out.u32(0);
+        out.u32(at as u32 + 4); // the distance back to the CIE
+        out.word(frame.start);
+        out.word(frame.end - frame.start);
+        out.uleb128(0); // augmentation data length
+        for (i, &(register, offset)) in frame::SAVED.iter().enumerate() {
+            if frame.saved & (1 << i) != 0 {
+                offset_rule(&mut out, register, offset);
+            }
+        }
+        finish_entry(&mut out, at);
+    }
+
+    out.u32(0);
+    (out.buf, fdes)
+}
+
+/// Records that `register` is stored `offset` bytes below the CFA.
+fn offset_rule(out: &mut Writer, register: u8, offset: u64) {
+    if register < 0x40 {
+        out.u8(DW_CFA_OFFSET | register);
+    } else {
+        out.u8(DW_CFA_OFFSET_EXTENDED);
+        out.uleb128(u64::from(register));
+    }
+    out.uleb128(offset / WORD as u64);
+}
+
+/// Pads the entry starting at `at` to a whole number of words and fills in
+/// its length.
+fn finish_entry(out: &mut Writer, at: usize) {
+    while out.len() & (WORD - 1) != 0 {
+        out.u8(DW_CFA_NOP);
+    }
+    let length = (out.len() - at - 4) as u32;
+    out.patch_u32(at, length);
+}
+
+#[cfg(all(test, any(all(target_arch = "x86_64", not(windows)), target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+    use crate::{CompiledCode, Jit, JitPointer, JitWord, Reg};
+
+    use std::convert::TryInto;
+    use std::os::raw::{c_int, c_void};
+
+    #[repr(C)]
+    struct UnwindContext {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        fn _Unwind_Backtrace(
+            trace: extern "C" fn(*mut UnwindContext, *mut c_void) -> c_int,
+            data: *mut c_void,
+        ) -> c_int;
+        fn _Unwind_GetIP(context: *mut UnwindContext) -> usize;
+        fn _Unwind_GetGR(context: *mut UnwindContext, index: c_int) -> usize;
+        fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut c_void;
+    }
+
+    extern "C" fn trace(context: *mut UnwindContext, frames: *mut c_void) -> c_int {
+        let frames = unsafe { &mut *(frames as *mut Vec<usize>) };
+        frames.push(unsafe { _Unwind_GetIP(context) });
+        0
+    }
+
+    /// Called from generated code; walks the stack through it, keeping the
+    /// return addresses in `frames`.
+    extern "C" fn backtrace(frames: *mut Vec<usize>) -> JitWord {
+        unsafe { _Unwind_Backtrace(trace, frames as *mut c_void) };
+        7
+    }
+
+    #[inline(never)]
+    fn enter(code: &CompiledCode<extern "C" fn(*mut Vec<usize>) -> JitWord>, frames: &mut Vec<usize>) -> JitWord {
+        code.call(frames)
+    }
+
+    /// Returns the start of the function containing the return address `ip`.
+    fn enclosing(ip: usize) -> usize {
+        unsafe { _Unwind_FindEnclosingFunction((ip - 1) as *mut c_void) as usize }
+    }
+
+    #[test]
+    fn section_layout() {
+        let frames = [
+            Frame { start: 0x1000, end: 0x1040, saved: 0 },
+            Frame { start: 0x1040, end: 0x10a0, saved: 0b11 },
+        ];
+        let (section, fdes) = eh_frame(&frames);
+        let u32_at = |at: usize| u32::from_ne_bytes(section[at..at + 4].try_into().unwrap());
+        let word_at = |at: usize| usize::from_ne_bytes(section[at..at + WORD].try_into().unwrap());
+
+        // Walk the entries by their lengths, up to the terminator.
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while u32_at(at) != 0 {
+            assert_eq!(u32_at(at) as usize % WORD, WORD - 4);
+            entries.push(at);
+            at += 4 + u32_at(at) as usize;
+        }
+        assert_eq!(at + 4, section.len());
+        assert_eq!(entries[1..], fdes[..]);
+
+        assert_eq!(u32_at(4), 0);
+        assert_eq!(&section[9..12], b"zR\0");
+        for (frame, &fde) in frames.iter().zip(&fdes) {
+            assert_eq!(u32_at(fde + 4) as usize, fde + 4);
+            assert_eq!(word_at(fde + 8), frame.start);
+            assert_eq!(word_at(fde + 8 + WORD), frame.end - frame.start);
+        }
+
+        // Only the second function has rules for saved registers.
+        let rules = |fde: usize| section[fde + 8 + 2 * WORD + 1..].iter().take_while(|&&b| b != 0).count();
+        assert_eq!(rules(fdes[0]), 0);
+        assert!(rules(fdes[1]) >= 4);
+    }
+
+    #[test]
+    fn registers_functions() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        js.reti(1);
+        js.epilog();
+        js.prolog();
+        js.reti(2);
+        js.epilog();
+
+        let code = js.emit().unwrap().into_code();
+        let unwind = code.unwind.as_ref().unwrap();
+        if cfg!(target_os = "macos") {
+            assert_eq!(unwind.entries.len(), 2);
+        } else {
+            assert_eq!(unwind.entries, [0]);
+        }
+    }
+
+    #[test]
+    fn unwinds_through_code() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.prolog();
+        let frames = js.arg::<JitPointer>();
+        js.getarg(Reg::R(0), &frames);
+        js.prepare();
+        js.pushargr(Reg::R(0));
+        js.finishi(backtrace as JitPointer);
+        js.retval(Reg::R(0));
+        js.addi(Reg::R(0), Reg::R(0), 1);
+        js.retr(Reg::R(0));
+        js.epilog();
+
+        let code = unsafe { js.emit().unwrap().into_code().cast::<extern "C" fn(*mut Vec<usize>) -> JitWord>() };
+        let mut frames = Vec::new();
+        assert_eq!(enter(&code, &mut frames), 8);
+
+        // The unwinder finds the generated function from the callback, and
+        // the Rust function that called it from there.
+        let generated = frames
+            .iter()
+            .position(|&ip| code.contains((ip - 1) as JitPointer))
+            .expect("no frame in the generated code");
+        assert!(frames[..generated].iter().any(|&ip| enclosing(ip) == backtrace as JitPointer as usize));
+        assert_eq!(enclosing(frames[generated]), code.entry_address() as usize);
+        assert!(frames[generated + 1..].iter().any(|&ip| enclosing(ip) == enter as JitPointer as usize));
+    }
+
+    /// The callee-saved registers of the target, in DWARF numbers.
+    #[cfg(target_arch = "x86_64")]
+    const CALLEE_SAVED: &[c_int] = &[3, 12, 13, 14, 15];
+    #[cfg(target_arch = "aarch64")]
+    const CALLEE_SAVED: &[c_int] = &[19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 72, 73, 74, 75, 76, 77, 78, 79];
+
+    /// A frame found by `registers`: its return address, and the values of
+    /// `CALLEE_SAVED` in it.
+    type Registers = (usize, Vec<usize>);
+
+    extern "C" fn trace_registers(context: *mut UnwindContext, frames: *mut c_void) -> c_int {
+        let frames = unsafe { &mut *(frames as *mut Vec<Registers>) };
+        let values = CALLEE_SAVED.iter().map(|&r| unsafe { _Unwind_GetGR(context, r) }).collect();
+        frames.push((unsafe { _Unwind_GetIP(context) }, values));
+        0
+    }
+
+    /// Called from generated code; records the callee-saved registers of
+    /// each frame as the unwinder restores them.
+    extern "C" fn registers(frames: *mut Vec<Registers>) {
+        unsafe { _Unwind_Backtrace(trace_registers, frames as *mut c_void) };
+    }
+
+    #[test]
+    fn restores_saved_registers() {
+        const KEPT: JitWord = 0x5eed_0000;
+        let mut jit = Jit::new();
+
+        // Overwrites every callee-saved register that lightning hands out,
+        // which its prolog saves, before calling `registers`.
+        let mut js = jit.new_state();
+        js.prolog();
+        let frames = js.arg::<JitPointer>();
+        js.getarg(Reg::R(0), &frames);
+        for (i, v) in Reg::all_v().enumerate() {
+            js.movi(v, i as JitWord);
+        }
+        if cfg!(target_arch = "aarch64") {
+            for f in crate::FReg::all() {
+                js.movi_d(f, -1.0);
+            }
+        }
+        js.prepare();
+        js.pushargr(Reg::R(0));
+        js.finishi(registers as JitPointer);
+        js.ret();
+        js.epilog();
+        let inner = js.emit().unwrap().into_code();
+
+        // Keeps known values in those registers across a call to `inner`.
+        let mut js = jit.new_state();
+        js.prolog();
+        let frames = js.arg::<JitPointer>();
+        js.getarg(Reg::R(0), &frames);
+        for (i, v) in Reg::all_v().enumerate() {
+            js.movi(v, KEPT + i as JitWord);
+        }
+        if cfg!(target_arch = "aarch64") {
+            for (i, f) in crate::FReg::all().enumerate() {
+                js.movi_d(f, f64::from_bits(KEPT as u64 + 0x100 + i as u64));
+            }
+        }
+        js.prepare();
+        js.pushargr(Reg::R(0));
+        js.finishi(inner.entry_address());
+        js.ret();
+        js.epilog();
+        let outer = unsafe { js.emit().unwrap().into_code().cast::<extern "C" fn(*mut Vec<Registers>)>() };
+
+        let mut frames = Vec::new();
+        outer.call(&mut frames);
+
+        // In the frame of `outer`, the unwinder finds the values it kept, which
+        // it can only do with the rules for where `inner` saved them.
+        let (_, values) = frames
+            .iter()
+            .find(|(ip, _)| outer.contains((ip - 1) as JitPointer))
+            .expect("no frame in the outer function");
+        for i in 0..Reg::all_v().count() {
+            let kept = (KEPT + i as JitWord) as usize;
+            assert!(values.contains(&kept), "{:#x} is lost: {:x?}", kept, values);
+        }
+        if cfg!(target_arch = "aarch64") {
+            for i in 0..crate::FReg::all().count() {
+                let kept = KEPT as usize + 0x100 + i;
+                assert!(values.contains(&kept), "{:#x} is lost: {:x?}", kept, values);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_code_without_functions() {
+        let mut jit = Jit::new();
+        let mut js = jit.new_state();
+        js.movi(crate::Reg::R(0), 1);
+
+        let code = js.emit().unwrap().into_code();
+        assert!(code.unwind.is_none());
+    }
+}